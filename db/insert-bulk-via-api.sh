@@ -0,0 +1,10 @@
+#!/bin/sh
+
+key="$1"
+input="$2"
+
+test -z "$key" && echo "Usage: $0 <api-key> ./path/to/url.file" && exit 1
+test ! -r "$input" && echo "Usage: $0 <api-key> ./path/to/url.file" && exit 1
+
+sed 's/.*/{"url":"&","title":"an example","description":"totally examplary url it is"}/' $input |
+  curl -X POST localhost:8080/api/v1/links/bulk -H "Authorization: Bearer $key" -H 'Content-Type: application/x-ndjson' --data-binary @-
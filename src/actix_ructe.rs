@@ -5,11 +5,11 @@ use std::io::Write;
 macro_rules! render {
     ($template:path) => (super::actix_ructe::Render(|o| $template(o)));
     ($template:path, $($arg:expr),*) => {{
-        use crate::actix_ructe::Render;
+        use $crate::actix_ructe::Render;
         Render(|o| $template(o, $($arg),*))
     }};
     ($template:path, $($arg:expr),* ,) => {{
-        use actix_ructe::Render;
+        use $crate::actix_ructe::Render;
         Render(|o| $template(o, $($arg),*))
     }};
 }
@@ -0,0 +1,433 @@
+//! Versioned JSON REST API, mounted at `/api/v1`.
+//!
+//! Every endpoint except fetching a single link requires an API key,
+//! sent as `Authorization: Bearer $key` header.
+
+use super::db::{self, DBValue, Link, LinkPostData, Queries, UrlPatchData, UrlPostData};
+use super::openapi;
+use super::policy;
+use super::response_types::{BulkResult, Error, LinkList, LinkResponse, RecheckResult};
+use super::server::{validate_url, Cfg, DomainPolicy, Limiter, MetadataQueue, Resolve, DB};
+use super::target::TargetCheck;
+use super::template;
+use super::{render, templates};
+use actix_web::{
+    self,
+    http::header::{AUTHORIZATION, CONTENT_TYPE, LOCATION},
+    web, HttpRequest, HttpResponse, Scope,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Query parameters for listing links
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct ListQuery {
+    pub cursor: Option<String>,
+    pub limit: Option<u32>,
+}
+
+/// Reads the API key from the `Authorization: Bearer $key` header
+fn api_key(req: &HttpRequest) -> Result<String, Error> {
+    req.headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|key| key.trim().to_owned())
+        .ok_or_else(Error::unauthorized)
+}
+
+/// Fetches a single link from the database
+async fn fetch_link(db: &DB, short_code: String) -> Result<Link, Error> {
+    match db::query(db, Queries::GetLink(short_code)).await? {
+        DBValue::Link(link) => Ok(*link),
+        value => {
+            debug!("Got unexpected type back from GetLink query: {:#?}", value);
+            Err(Error::internal())
+        }
+    }
+}
+
+/// Link creation handler
+/// `POST /api/v1/links -d '{"url": "https://example.com"}'`
+/// responds with the created link object. With `deduplicate` enabled, an
+/// existing link of the user to the same target is returned with 200 instead.
+#[actix_web::post("/links")]
+async fn create_link(
+    req: HttpRequest,
+    data: web::Json<LinkPostData>,
+    db: DB,
+    config: Cfg,
+    queue: MetadataQueue,
+    policy: DomainPolicy,
+    resolver: Resolve,
+) -> Result<HttpResponse, Error> {
+    let key = api_key(&req)?;
+    let targets = TargetCheck::new(&req, &db, &config, &resolver, &policy, &key).await?;
+    let link_data = targets.check_link(data.into_inner()).await?;
+
+    let url_data = UrlPostData {
+        link: link_data.clone(),
+        key,
+    };
+
+    let query = Queries::StoreNewURL(url_data, config.deduplicate);
+    let (code, is_new) = match db::query(&db, query).await? {
+        DBValue::Stored(mut stored) if stored.len() == 1 => stored.remove(0),
+        value => {
+            debug!(
+                "Got unexpected type back from StoreNewURL query: {:#?}",
+                value
+            );
+            return Err(Error::internal());
+        }
+    };
+    let link = fetch_link(&db, code).await?;
+    let mut response = LinkResponse::new(link, &config);
+    response.created = Some(is_new);
+
+    if !is_new {
+        return Ok(HttpResponse::Ok().json(response));
+    }
+    queue.push(&response.code, &link_data);
+    Ok(HttpResponse::Created()
+        .header(LOCATION, format!("/api/v1/links/{}", response.code))
+        .json(response))
+}
+
+/// Parses the body of a bulk request, which is either a JSON array or,
+/// if sent with an NDJSON content type, one JSON object per line.
+/// Entries that cannot be parsed are kept as error to report them per item.
+fn parse_bulk_body(
+    req: &HttpRequest,
+    body: &[u8],
+) -> Result<Vec<Result<LinkPostData, Error>>, Error> {
+    let is_ndjson = req
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.contains("ndjson") || value.contains("jsonlines"))
+        .unwrap_or(false);
+
+    let entries: Vec<serde_json::Value> = if is_ndjson {
+        std::str::from_utf8(body)
+            .map_err(|_| Error::bad_request("invalid_ndjson", "Invalid NDJSON, not UTF-8 encoded"))?
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| serde_json::from_str(line).unwrap_or(serde_json::Value::Null))
+            .collect()
+    } else {
+        serde_json::from_slice(body).map_err(|err| {
+            Error::invalid_request(
+                "invalid_json",
+                "Invalid JSON, expected an array",
+                &err.to_string(),
+            )
+        })?
+    };
+
+    Ok(entries
+        .into_iter()
+        .map(|entry| {
+            serde_json::from_value(entry).map_err(|err| {
+                Error::invalid_request("invalid_entry", "Invalid entry", &err.to_string())
+            })
+        })
+        .collect())
+}
+
+/// Bulk link creation handler
+/// `POST /api/v1/links/bulk -d '[{"url": "https://example.com"}, …]'`
+/// or with `Content-Type: application/x-ndjson` and one link per line.
+/// All valid entries are stored in one transaction, entries with a name that
+/// is already taken fail on their own. Responds with one result per entry,
+/// containing either the short code or an error message.
+#[actix_web::post("/links/bulk")]
+async fn create_links(
+    req: HttpRequest,
+    body: web::Bytes,
+    db: DB,
+    config: Cfg,
+    queue: MetadataQueue,
+    policy: DomainPolicy,
+    resolver: Resolve,
+) -> Result<HttpResponse, Error> {
+    let key = api_key(&req)?;
+    let entries = parse_bulk_body(&req, &body)?;
+    let targets = TargetCheck::new(&req, &db, &config, &resolver, &policy, &key).await?;
+
+    if entries.len() > config.max_bulk_size {
+        return Err(Error::too_many_entries(config.max_bulk_size));
+    }
+
+    let mut results = Vec::with_capacity(entries.len());
+    let mut valid = Vec::new();
+    let mut valid_indices = Vec::new();
+
+    for (index, entry) in entries.into_iter().enumerate() {
+        let checked = match entry {
+            Ok(link) => targets.check_link(link).await,
+            Err(err) => Err(err),
+        };
+
+        match checked {
+            Ok(link) => {
+                valid_indices.push(index);
+                valid.push(link);
+            }
+            Err(err) => results.push(BulkResult::failed(index, err)),
+        }
+    }
+
+    if !valid.is_empty() {
+        let query = Queries::StoreNewURLs(key, valid.clone(), config.deduplicate);
+        match db::query(&db, query).await? {
+            DBValue::StoredEach(stored) => {
+                let entries = valid_indices.into_iter().zip(valid.iter().zip(stored));
+                for (index, (link, stored)) in entries {
+                    let (code, is_new) = match stored {
+                        Some(stored) => stored,
+                        None => {
+                            let err = Error::name_taken().on_field("name");
+                            results.push(BulkResult::failed(index, err));
+                            continue;
+                        }
+                    };
+                    if is_new {
+                        queue.push(&code, link);
+                    }
+                    let path = template::path(&code, link.name.as_deref(), &link.url);
+                    let short_url = config.short_url_in(link.domain.as_deref(), path);
+                    results.push(BulkResult::created(index, code, short_url, is_new));
+                }
+            }
+            value => {
+                debug!(
+                    "Got unexpected type back from StoreNewURLs query: {:#?}",
+                    value
+                );
+                return Err(Error::internal());
+            }
+        }
+    }
+
+    results.sort_by_key(|result| result.index);
+    Ok(HttpResponse::Ok().json(results))
+}
+
+/// Link listing handler
+/// `GET /api/v1/links?cursor=1z5&limit=50`
+/// responds with a page of the users links, ordered by creation, and
+/// the cursor to fetch the next page with
+#[actix_web::get("/links")]
+async fn list_links(
+    req: HttpRequest,
+    query: web::Query<ListQuery>,
+    db: DB,
+    config: Cfg,
+) -> Result<HttpResponse, Error> {
+    let key = api_key(&req)?;
+    let query = query.into_inner();
+    let limit = query
+        .limit
+        .unwrap_or(config.page_size)
+        .min(config.max_page_size)
+        .max(1);
+
+    match db::query(&db, Queries::ListLinks(key, query.cursor, limit)).await? {
+        DBValue::Links(links, next_cursor) => Ok(HttpResponse::Ok().json(LinkList {
+            links: links
+                .into_iter()
+                .map(|link| LinkResponse::new(link, &config))
+                .collect(),
+            next_cursor,
+        })),
+        value => {
+            debug!(
+                "Got unexpected type back from ListLinks query: {:#?}",
+                value
+            );
+            Err(Error::internal())
+        }
+    }
+}
+
+/// Link metadata handler
+/// `GET /api/v1/links/1z5`
+/// responds with the link object, this does not count as visit
+#[actix_web::get("/links/{short_code}")]
+async fn get_link(path: web::Path<String>, db: DB, config: Cfg) -> Result<HttpResponse, Error> {
+    let link = fetch_link(&db, path.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(LinkResponse::new(link, &config)))
+}
+
+/// Link update handler
+/// `PATCH /api/v1/links/1z5 -d '{"title": "new title"}'`
+/// updates all given fields and responds with the updated link object
+#[actix_web::patch("/links/{short_code}")]
+async fn update_link(
+    req: HttpRequest,
+    path: web::Path<String>,
+    data: web::Json<UrlPatchData>,
+    db: DB,
+    config: Cfg,
+    policy: DomainPolicy,
+    resolver: Resolve,
+) -> Result<HttpResponse, Error> {
+    let key = api_key(&req)?;
+    let short_code = path.into_inner();
+    let mut data = data.into_inner();
+    if let Some(url) = &data.url {
+        let targets = TargetCheck::new(&req, &db, &config, &resolver, &policy, &key).await?;
+        let is_named = fetch_link(&db, short_code.clone()).await?.name.is_some();
+        let url = if is_named && template::is_template(url) {
+            targets.check_template(url).await
+        } else {
+            targets.check(url, Some(&short_code)).await
+        };
+        data.url = Some(url.map_err(|err| err.on_field("url"))?);
+    }
+    if let Some(Some(image)) = &data.image {
+        validate_url(&req, image).map_err(|err| err.on_field("image"))?;
+    }
+
+    let query = Queries::UpdateLink(key, short_code, data);
+    match db::query(&db, query).await? {
+        DBValue::Link(link) => Ok(HttpResponse::Ok().json(LinkResponse::new(*link, &config))),
+        value => {
+            debug!(
+                "Got unexpected type back from UpdateLink query: {:#?}",
+                value
+            );
+            Err(Error::internal())
+        }
+    }
+}
+
+/// Link deletion handler
+/// `DELETE /api/v1/links/1z5`
+/// responds with 204 No Content, the short code will not be reused
+#[actix_web::delete("/links/{short_code}")]
+async fn delete_link(
+    req: HttpRequest,
+    path: web::Path<String>,
+    db: DB,
+) -> Result<HttpResponse, Error> {
+    let key = api_key(&req)?;
+    db::query(&db, Queries::DeleteLink(key, path.into_inner())).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Broken link listing handler, for admins only
+/// `GET /api/v1/admin/links/broken?cursor=1z5&limit=50`
+/// responds with a page of links of all users whose last health check
+/// failed, see the health field of each link for details
+#[actix_web::get("/admin/links/broken")]
+async fn list_broken_links(
+    req: HttpRequest,
+    query: web::Query<ListQuery>,
+    db: DB,
+    config: Cfg,
+) -> Result<HttpResponse, Error> {
+    let key = api_key(&req)?;
+    let query = query.into_inner();
+    let limit = query
+        .limit
+        .unwrap_or(config.page_size)
+        .min(config.max_page_size)
+        .max(1);
+
+    match db::query(&db, Queries::ListBrokenLinks(key, query.cursor, limit)).await? {
+        DBValue::Links(links, next_cursor) => Ok(HttpResponse::Ok().json(LinkList {
+            links: links
+                .into_iter()
+                .map(|link| LinkResponse::new(link, &config))
+                .collect(),
+            next_cursor,
+        })),
+        value => {
+            debug!(
+                "Got unexpected type back from ListBrokenLinks query: {:#?}",
+                value
+            );
+            Err(Error::internal())
+        }
+    }
+}
+
+/// Domain policy reload handler, for admins only
+/// `POST /api/v1/admin/domains/reload`
+/// reads the domain policy file again, checks all links against it and
+/// responds with the number of newly blocked and unblocked links
+#[actix_web::post("/admin/domains/reload")]
+async fn reload_domains(
+    req: HttpRequest,
+    db: DB,
+    policy: DomainPolicy,
+) -> Result<HttpResponse, Error> {
+    let key = api_key(&req)?;
+    db::query(&db, Queries::CheckAdmin(key)).await?;
+
+    policy.reload().map_err(|err| {
+        error!("Failed to reload domain policy: {}", err);
+        Error::bad_request("invalid_domain_policy", "Invalid domain policy file")
+    })?;
+
+    let (blocked, unblocked) = policy::recheck_links(&db, &policy.current())
+        .await
+        .map_err(Error::from)?;
+    Ok(HttpResponse::Ok().json(RecheckResult { blocked, unblocked }))
+}
+
+/// Rate limiter statistics handler, for admins only
+/// `GET /api/v1/admin/rate-limit`
+/// responds with the counters of the rate limiter
+#[actix_web::get("/admin/rate-limit")]
+async fn rate_limit_stats(
+    req: HttpRequest,
+    db: DB,
+    limiter: Limiter,
+) -> Result<HttpResponse, Error> {
+    let key = api_key(&req)?;
+    db::query(&db, Queries::CheckAdmin(key)).await?;
+
+    Ok(HttpResponse::Ok().json(limiter.stats()))
+}
+
+/// OpenAPI document handler
+/// `GET /api/v1/openapi.json`
+/// describes all endpoints with the schemas of their requests and
+/// responses, see openapi.rs
+#[actix_web::get("/openapi.json")]
+async fn openapi_document(config: Cfg) -> HttpResponse {
+    HttpResponse::Ok().json(openapi::document(&config))
+}
+
+/// API docs handler
+/// `GET /api/v1/docs`
+/// renders the endpoints and schemas of the OpenAPI document as HTML page
+#[actix_web::get("/docs")]
+async fn docs() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(render!(
+            templates::api_docs_html,
+            openapi::ENDPOINTS,
+            &openapi::schema_docs()
+        ))
+}
+
+/// Builds the `/api/v1` scope with all API resources
+pub fn scope() -> Scope {
+    web::scope("/api/v1")
+        .service(create_link) // POST /api/v1/links
+        .service(create_links) // POST /api/v1/links/bulk
+        .service(list_links) // GET /api/v1/links
+        .service(get_link) // GET /api/v1/links/123
+        .service(update_link) // PATCH /api/v1/links/123
+        .service(delete_link) // DELETE /api/v1/links/123
+        .service(list_broken_links) // GET /api/v1/admin/links/broken
+        .service(reload_domains) // POST /api/v1/admin/domains/reload
+        .service(rate_limit_stats) // GET /api/v1/admin/rate-limit
+        .service(openapi_document) // GET /api/v1/openapi.json
+        .service(docs) // GET /api/v1/docs
+}
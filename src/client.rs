@@ -0,0 +1,228 @@
+//! Resolves the address of the client behind a request.
+//!
+//! Reverse proxies name the client in a `Forwarded` or `X-Forwarded-For`
+//! header. These headers (and `X-Forwarded-Host` and `X-Forwarded-Proto`) are
+//! only honoured for requests from trusted proxies and removed from all other
+//! requests, so that clients cannot pick their own address to dodge the rate
+//! limiter or to forge log entries. Logging, rate limiting and the handlers
+//! all use the address resolved here.
+
+use super::config::ProxyConfig;
+use actix_web::{
+    dev::{RequestHead, ServiceRequest},
+    http::header::{HeaderMap, HeaderName, HeaderValue, FORWARDED},
+    HttpMessage,
+};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+
+/// The resolved address of the client, stored in the request extensions
+#[derive(Debug, Clone, Copy)]
+struct ClientAddr(IpAddr);
+
+/// An address or network like "10.0.0.0/8", "192.168.1.1" or "fd00::/8"
+#[derive(Debug, Clone)]
+struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Cidr, String> {
+        let (addr, prefix) = match value.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (value, None),
+        };
+        let addr = IpAddr::from_str(addr.trim())
+            .map(canonical)
+            .map_err(|_| format!("Invalid address {}", value))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(|| format!("Invalid prefix length in {}", value))?,
+            None => max,
+        };
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl Cidr {
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, canonical(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Treats IPv4 addresses mapped to IPv6, like ::ffff:127.0.0.1, as IPv4
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.octets() {
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => IpAddr::from([a, b, c, d]),
+            _ => ip,
+        },
+        _ => ip,
+    }
+}
+
+/// Parses a node of a forwarding header, like `1.2.3.4`, `1.2.3.4:5678`,
+/// `"[2001:db8::1]:5678"` or `2001:db8::1`. Obfuscated and unknown nodes
+/// result in None.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split(']').next()?.parse().ok();
+    }
+    node.parse::<IpAddr>()
+        .or_else(|_| node.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .ok()
+}
+
+/// Returns the nodes a request passed through, from the client to the last
+/// proxy, from the `Forwarded` header or, if there is none, `X-Forwarded-For`
+fn forwarded_nodes(headers: &HeaderMap) -> Vec<&str> {
+    let values = |name| {
+        headers
+            .get_all(name)
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+    };
+
+    let forwarded = values(FORWARDED)
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (name, value) = pair.split_once('=')?;
+                Some(value).filter(|_| name.trim().eq_ignore_ascii_case("for"))
+            })
+        })
+        .collect::<Vec<&str>>();
+    if !forwarded.is_empty() {
+        return forwarded;
+    }
+    values(HeaderName::from_static(X_FORWARDED_FOR)).collect()
+}
+
+/// Removes the `for` parameters from a `Forwarded` header, keeping `host`
+/// and `proto` of trusted proxies
+fn without_for(value: &str) -> String {
+    value
+        .split(',')
+        .map(|element| {
+            element
+                .split(';')
+                .filter(|pair| match pair.split_once('=') {
+                    Some((name, _)) => !name.trim().eq_ignore_ascii_case("for"),
+                    None => false,
+                })
+                .map(str::trim)
+                .collect::<Vec<&str>>()
+                .join(";")
+        })
+        .filter(|element| !element.is_empty())
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// Resolves client addresses with the list of trusted proxies, shared
+/// between all workers
+pub struct ClientResolver {
+    trusted: Vec<Cidr>,
+}
+
+impl ClientResolver {
+    pub fn new(config: &ProxyConfig) -> Result<ClientResolver, String> {
+        let trusted = config
+            .trusted
+            .iter()
+            .map(|cidr| cidr.parse())
+            .collect::<Result<Vec<Cidr>, String>>()?;
+        Ok(ClientResolver { trusted })
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    /// Returns the client address of a request from peer. Forwarded nodes are
+    /// followed from the last one as long as they are trusted proxies, so a
+    /// client can only prepend addresses that are never looked at.
+    fn resolve(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let mut client = canonical(peer);
+        if !self.is_trusted(client) {
+            return client;
+        }
+
+        for node in forwarded_nodes(headers).into_iter().rev() {
+            match parse_node(node).map(canonical) {
+                Some(ip) if self.is_trusted(ip) => client = ip,
+                Some(ip) => return ip,
+                None => break,
+            }
+        }
+        client
+    }
+
+    /// Resolves the client address of req and rewrites its forwarding headers,
+    /// so that the connection info of the request agrees with the resolver.
+    /// Forwarding headers of untrusted peers are removed, the ones of trusted
+    /// proxies are replaced by the resolved client address.
+    pub fn resolve_request(&self, req: &mut ServiceRequest) {
+        let peer = match req.peer_addr() {
+            Some(addr) => addr.ip(),
+            None => return,
+        };
+        let client = self.resolve(peer, req.headers());
+        let trusted = self.is_trusted(canonical(peer));
+        let headers = req.headers_mut();
+
+        if !trusted {
+            for name in &[X_FORWARDED_FOR, X_FORWARDED_HOST, X_FORWARDED_PROTO] {
+                headers.remove(*name);
+            }
+            headers.remove(FORWARDED);
+        } else {
+            let forwarded = headers
+                .get_all(FORWARDED)
+                .filter_map(|value| value.to_str().ok())
+                .map(without_for)
+                .filter(|value| !value.is_empty())
+                .collect::<Vec<String>>()
+                .join(", ");
+            headers.remove(FORWARDED);
+            if let Ok(value) = HeaderValue::from_str(&forwarded) {
+                if !forwarded.is_empty() {
+                    headers.insert(FORWARDED, value);
+                }
+            }
+            headers.insert(
+                HeaderName::from_static(X_FORWARDED_FOR),
+                HeaderValue::from_str(&client.to_string()).unwrap(),
+            );
+        }
+        req.extensions_mut().insert(ClientAddr(client));
+    }
+}
+
+/// Returns the client address of a request, as resolved by the ClientResolver
+pub fn address(head: &RequestHead) -> Option<IpAddr> {
+    let resolved = head.extensions().get::<ClientAddr>().map(|addr| addr.0);
+    resolved.or_else(|| head.peer_addr.map(|addr| canonical(addr.ip())))
+}
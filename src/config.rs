@@ -0,0 +1,506 @@
+use super::db::Link;
+use super::redirect::{RedirectMode, UnfurlMode};
+use super::template;
+use failure::{format_err, Error};
+use serde::Deserialize;
+use std::path::Path;
+use url::Url;
+
+/// Default configuration file name, looked up in the working directory
+pub const DEFAULT_CONFIG_FILE: &str = "k0r.toml";
+
+/// Service configuration, read from a TOML file.
+/// Every field is optional and falls back to its default value, so an
+/// empty (or missing) configuration file results in the same setup as before,
+/// except for redirects: they default to 302 instead of the former 301, which
+/// browsers cached forever.
+///
+/// ```toml
+/// listen = "127.0.0.1:8080"
+/// base_url = "http://127.0.0.1:8080"
+/// redirect = "302"
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Address and port the server binds to
+    pub listen: String,
+    /// Public address of the service, used to build absolute short URLs
+    pub base_url: String,
+    /// Default and maximum page size for listing links via the API
+    pub page_size: u32,
+    pub max_page_size: u32,
+    /// Maximum number of links that can be created with one bulk request
+    pub max_bulk_size: usize,
+    /// Default redirect mode for links without their own
+    pub redirect: RedirectMode,
+    /// Seconds before the interstitial page redirects
+    pub interstitial_delay: u32,
+    /// Seconds permanent redirects may be cached by clients
+    pub permanent_redirect_max_age: u32,
+    /// Default for which clients get a page with link metadata
+    pub unfurl: UnfurlMode,
+    /// Parts of user agents that identify crawlers, case insensitive
+    pub crawler_user_agents: Vec<String>,
+    /// Image for link cards, used for links without their own
+    pub card_image: Option<String>,
+    /// Outgoing requests to link targets
+    pub fetch: FetchConfig,
+    /// Fetching missing link metadata from link targets
+    pub metadata: MetadataConfig,
+    /// Periodic checks of link targets
+    pub health: HealthConfig,
+    /// URL schemes that can be shortened, more can be allowed per user
+    /// in the domain policy
+    pub schemes: Vec<String>,
+    /// TOML file with domain allow and deny lists, see policy.rs
+    pub domain_policy: Option<String>,
+    /// Resolving targets that are short links themselves
+    pub resolve: ResolveConfig,
+    /// Normalizing link targets before storing them
+    pub normalize: NormalizeConfig,
+    /// Returns the existing link instead of creating a new one if the same
+    /// user shortens the same (normalized) target again
+    pub deduplicate: bool,
+    /// Hostnames with their own namespace for link names
+    pub domains: Vec<DomainConfig>,
+    /// Rate limiting of short link lookups
+    pub rate_limit: RateLimitConfig,
+    /// Reverse proxies and load balancers in front of the service
+    pub proxy: ProxyConfig,
+    /// Cross-origin requests to the API from browsers
+    pub cors: CorsConfig,
+    /// Prometheus metrics of the service
+    pub metrics: MetricsConfig,
+    /// Health and readiness probes for orchestrators
+    pub probes: ProbesConfig,
+}
+
+/// Configuration of outgoing requests, in the [fetch] section
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct FetchConfig {
+    /// Seconds to wait for a response
+    pub timeout: u64,
+    pub max_redirects: usize,
+    pub user_agent: String,
+    /// Allows requests to private and local addresses, like 127.0.0.1
+    pub allow_private_addresses: bool,
+}
+
+impl Default for FetchConfig {
+    fn default() -> Self {
+        FetchConfig {
+            timeout: 5,
+            max_redirects: 5,
+            user_agent: format!("k0r/{}", env!("CARGO_PKG_VERSION")),
+            allow_private_addresses: false,
+        }
+    }
+}
+
+/// Configuration of the metadata fetcher, in the [metadata] section
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MetadataConfig {
+    pub enabled: bool,
+    /// Maximum bytes read from a response, metadata is usually at the top
+    pub max_size: usize,
+    /// Content types that are parsed, everything else is ignored
+    pub content_types: Vec<String>,
+    /// Maximum number of links waiting for their metadata, further links
+    /// are skipped until the worker catches up
+    pub queue_size: usize,
+}
+
+impl Default for MetadataConfig {
+    fn default() -> Self {
+        MetadataConfig {
+            enabled: false,
+            max_size: 256 * 1024,
+            content_types: vec![
+                String::from("text/html"),
+                String::from("application/xhtml+xml"),
+            ],
+            queue_size: 1000,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: String::from("127.0.0.1:8080"),
+            base_url: String::from("http://127.0.0.1:8080"),
+            page_size: 50,
+            max_page_size: 500,
+            max_bulk_size: 1000,
+            redirect: RedirectMode::Found,
+            interstitial_delay: 2,
+            permanent_redirect_max_age: 24 * 60 * 60,
+            unfurl: UnfurlMode::Crawlers,
+            crawler_user_agents: [
+                "facebookexternalhit",
+                "Facebot",
+                "Twitterbot",
+                "Slackbot",
+                "Discordbot",
+                "TelegramBot",
+                "WhatsApp",
+                "LinkedInBot",
+                "Mastodon",
+                "SkypeUriPreview",
+                "redditbot",
+                "Pinterest",
+                "vkShare",
+                "Iframely",
+                "Embedly",
+            ]
+            .iter()
+            .map(|ua| ua.to_string())
+            .collect(),
+            card_image: None,
+            fetch: FetchConfig::default(),
+            metadata: MetadataConfig::default(),
+            health: HealthConfig::default(),
+            schemes: vec![String::from("http"), String::from("https")],
+            domain_policy: None,
+            resolve: ResolveConfig::default(),
+            normalize: NormalizeConfig::default(),
+            deduplicate: false,
+            domains: Vec::new(),
+            rate_limit: RateLimitConfig::default(),
+            proxy: ProxyConfig::default(),
+            cors: CorsConfig::default(),
+            metrics: MetricsConfig::default(),
+            probes: ProbesConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Reads the configuration from the given TOML file
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Config, Error> {
+        let content = std::fs::read_to_string(path)?;
+        Config::from_toml(&content)
+    }
+
+    /// Parses and validates the configuration from a TOML string
+    pub fn from_toml(content: &str) -> Result<Config, Error> {
+        let config: Config = toml::from_str(content)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks values that deserialize fine but cannot be used together
+    fn validate(&self) -> Result<(), Error> {
+        if self.max_page_size < 1 {
+            return Err(format_err!("max_page_size must be at least 1"));
+        }
+        if self.page_size < 1 || self.page_size > self.max_page_size {
+            return Err(format_err!(
+                "page_size must be between 1 and max_page_size ({})",
+                self.max_page_size
+            ));
+        }
+        Ok(())
+    }
+
+    /// Builds the absolute short URL for the given short code
+    pub fn short_url(&self, short_code: &str) -> String {
+        self.short_url_in(None, short_code)
+    }
+
+    /// Builds the absolute short URL for path on the given domain,
+    /// or on base_url for links without domain
+    pub fn short_url_in(&self, domain: Option<&str>, path: &str) -> String {
+        let base_url = domain
+            .and_then(|host| self.domain(host))
+            .map_or(&self.base_url, |domain| &domain.base_url);
+        format!("{}/{}", base_url.trim_end_matches('/'), path)
+    }
+
+    /// Builds the absolute short URL of link, see template::path
+    pub fn link_url(&self, link: &Link) -> String {
+        let path = template::path(&link.code, link.name.as_deref(), &link.url);
+        self.short_url_in(link.domain.as_deref(), path)
+    }
+
+    /// Looks up the configured domain for a host like it is sent in the
+    /// Host header, with port if it is not the default one
+    pub fn domain(&self, host: &str) -> Option<&DomainConfig> {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        self.domains
+            .iter()
+            .find(|domain| domain.host().as_ref() == Some(&host))
+    }
+}
+
+/// Configuration of the rate limiting of short link lookups per client
+/// address, in the [rate_limit] section
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    /// Lookups per client within window seconds
+    pub requests: u32,
+    pub window: u64,
+    /// Not found responses in a row until a client is banned, 0 disables
+    pub max_not_found: u32,
+    /// Lookups of consecutive short codes in a row until a client is
+    /// banned, 0 disables
+    pub max_sequential: u32,
+    /// Seconds a client stays banned
+    pub ban_duration: u64,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            enabled: false,
+            requests: 60,
+            window: 60,
+            max_not_found: 20,
+            max_sequential: 10,
+            ban_duration: 10 * 60,
+        }
+    }
+}
+
+/// Configuration of the proxies in front of the service, in the [proxy]
+/// section. Only trusted proxies can tell the address of the client.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ProxyConfig {
+    /// Addresses and networks of trusted proxies, like "10.0.0.0/8" or "::1"
+    pub trusted: Vec<String>,
+    /// Expects a PROXY protocol header (version 1 or 2) at the start of
+    /// every connection, like HAProxy sends it with `send-proxy`
+    pub protocol: bool,
+    /// Seconds to wait for the PROXY protocol header
+    pub protocol_timeout: u64,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        ProxyConfig {
+            trusted: vec![String::from("127.0.0.0/8"), String::from("::1")],
+            protocol: false,
+            protocol_timeout: 5,
+        }
+    }
+}
+
+/// Configuration of cross-origin requests to the API, in the [cors] section
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CorsConfig {
+    /// Origins like "https://dashboard.example.com" that can call the API,
+    /// "*" allows all origins and an empty list disables CORS
+    pub origins: Vec<String>,
+    /// Methods and request headers cross-origin requests can use
+    pub methods: Vec<String>,
+    pub headers: Vec<String>,
+    /// Seconds browsers may cache the response to a preflight request
+    pub max_age: u64,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            origins: Vec::new(),
+            methods: ["GET", "POST", "PATCH", "DELETE"]
+                .iter()
+                .map(|method| String::from(*method))
+                .collect(),
+            headers: vec![String::from("Authorization"), String::from("Content-Type")],
+            max_age: 24 * 60 * 60,
+        }
+    }
+}
+
+/// Configuration of the Prometheus metrics endpoint, in the [metrics] section
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MetricsConfig {
+    pub enabled: bool,
+    /// Separate address and port to serve the metrics on, like
+    /// "127.0.0.1:9180", instead of the one of the service
+    pub listen: Option<String>,
+    pub path: String,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig {
+            enabled: false,
+            listen: None,
+            path: String::from("/metrics"),
+        }
+    }
+}
+
+/// Configuration of the health and readiness probes, in the [probes] section
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ProbesConfig {
+    /// Seconds each readiness check may take before it fails
+    pub timeout: u64,
+    /// Seconds the readiness probe fails on SIGTERM or SIGINT before the
+    /// service shuts down, so that load balancers stop sending requests
+    /// first. 0 shuts down right away.
+    pub shutdown_delay: u64,
+}
+
+impl Default for ProbesConfig {
+    fn default() -> Self {
+        ProbesConfig {
+            timeout: 2,
+            shutdown_delay: 0,
+        }
+    }
+}
+
+/// A hostname with its own namespace for link names, in a [[domains]] section.
+/// Short codes work on every domain.
+#[derive(Debug, Clone, Deserialize)]
+pub struct DomainConfig {
+    /// Public address of the domain, like "https://go.example.com"
+    pub base_url: String,
+    /// Ids of the users that can create links on this domain
+    #[serde(default)]
+    pub users: Vec<i64>,
+    /// HTML file served at / instead of the shortening form
+    pub index: Option<String>,
+}
+
+impl DomainConfig {
+    /// The host of base_url, with port if it is not the default one
+    pub fn host(&self) -> Option<String> {
+        let url = Url::parse(&self.base_url).ok()?;
+        let host = url.host_str()?.to_ascii_lowercase();
+        Some(match url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host,
+        })
+    }
+}
+
+/// Configuration of the link health checker, in the [health] section
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HealthConfig {
+    pub enabled: bool,
+    /// Seconds until a link is checked again
+    pub recheck_after: u64,
+    /// Milliseconds to wait between two requests
+    pub delay: u64,
+    /// URL to redirect to instead of dead targets, where {url} is replaced
+    /// with the target, like "https://web.archive.org/web/{url}"
+    pub fallback: Option<String>,
+    /// Failed checks in a row until a target is considered dead
+    pub fallback_after: i64,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            enabled: false,
+            recheck_after: 7 * 24 * 60 * 60,
+            delay: 1000,
+            fallback: None,
+            fallback_after: 3,
+        }
+    }
+}
+
+/// Configuration of short link resolution, in the [resolve] section
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ResolveConfig {
+    /// Domains of this service in addition to the host of base_url,
+    /// optionally with port like "localhost:8080"
+    pub own_domains: Vec<String>,
+    /// Replaces links to other link shorteners with their target
+    pub unwrap_shorteners: bool,
+    pub shorteners: Vec<String>,
+    /// Maximum number of short links to follow
+    pub max_hops: usize,
+}
+
+impl Default for ResolveConfig {
+    fn default() -> Self {
+        ResolveConfig {
+            own_domains: Vec::new(),
+            unwrap_shorteners: false,
+            shorteners: [
+                "bit.ly",
+                "buff.ly",
+                "cutt.ly",
+                "goo.gl",
+                "is.gd",
+                "ow.ly",
+                "rebrand.ly",
+                "t.co",
+                "t.ly",
+                "tinyurl.com",
+                "v.gd",
+            ]
+            .iter()
+            .map(|domain| String::from(*domain))
+            .collect(),
+            max_hops: 5,
+        }
+    }
+}
+
+/// Configuration of link target normalization, in the [normalize] section
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct NormalizeConfig {
+    pub enabled: bool,
+    /// Query parameters to remove, patterns ending with * match prefixes
+    pub strip_params: Vec<String>,
+    /// Sorts the remaining query parameters by name
+    pub sort_params: bool,
+}
+
+impl Default for NormalizeConfig {
+    fn default() -> Self {
+        NormalizeConfig {
+            enabled: false,
+            strip_params: [
+                "utm_*", "fbclid", "gclid", "dclid", "msclkid", "yclid", "mc_cid", "mc_eid",
+                "igshid", "_hsenc", "_hsmi",
+            ]
+            .iter()
+            .map(|param| String::from(*param))
+            .collect(),
+            sort_params: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_config_is_default() {
+        let config = Config::from_toml("").unwrap();
+        assert_eq!(config.page_size, 50);
+        assert_eq!(config.max_page_size, 500);
+    }
+
+    #[test]
+    fn rejects_zero_max_page_size() {
+        assert!(Config::from_toml("max_page_size = 0").is_err());
+        assert!(Config::from_toml("page_size = 0").is_err());
+    }
+
+    #[test]
+    fn rejects_page_size_above_max() {
+        assert!(Config::from_toml("page_size = 100\nmax_page_size = 10").is_err());
+        assert!(Config::from_toml("page_size = 10\nmax_page_size = 10").is_ok());
+    }
+}
@@ -0,0 +1,363 @@
+//! Cross-origin resource sharing for the API, so that web dashboards and
+//! browser extensions on other origins can call it.
+//!
+//! Only API routes (`/api/…` and the JSON request to `POST /`) get CORS
+//! headers, redirects, previews, QR codes and static files never do.
+//! Preflight requests are answered right away, without reaching the handlers.
+
+use super::config::CorsConfig;
+use super::response_types::Error;
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{
+        HeaderMap, HeaderName, HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS,
+        ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS,
+        ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD,
+        ORIGIN, VARY,
+    },
+    http::Method,
+    HttpResponse,
+};
+
+/// The CORS configuration with methods and headers parsed, shared between
+/// all workers
+pub struct Cors {
+    origins: Vec<String>,
+    any_origin: bool,
+    methods: Vec<Method>,
+    headers: Vec<HeaderName>,
+    max_age: u64,
+}
+
+/// Joins a list of methods or headers for a response header
+fn join<T: AsRef<str>>(values: &[T]) -> HeaderValue {
+    let values = values.iter().map(AsRef::as_ref).collect::<Vec<&str>>();
+    HeaderValue::from_str(&values.join(", ")).unwrap()
+}
+
+/// Only the API and the JSON request to add URLs, see the module docs
+fn is_api(req: &ServiceRequest) -> bool {
+    let path = req.path();
+    path.starts_with("/api/")
+        || (path == "/" && req.method() != Method::GET && req.method() != Method::HEAD)
+}
+
+impl Cors {
+    pub fn new(config: &CorsConfig) -> Cors {
+        let methods = config
+            .methods
+            .iter()
+            .filter_map(|method| match Method::from_bytes(method.as_bytes()) {
+                Ok(method) => Some(method),
+                Err(_) => {
+                    warn!("Ignoring invalid CORS method {}", method);
+                    None
+                }
+            })
+            .collect();
+        let headers = config
+            .headers
+            .iter()
+            .filter_map(|header| match HeaderName::from_bytes(header.as_bytes()) {
+                Ok(header) => Some(header),
+                Err(_) => {
+                    warn!("Ignoring invalid CORS header {}", header);
+                    None
+                }
+            })
+            .collect();
+
+        Cors {
+            origins: config
+                .origins
+                .iter()
+                .map(|origin| origin.trim_end_matches('/').to_ascii_lowercase())
+                .collect(),
+            any_origin: config.origins.iter().any(|origin| origin == "*"),
+            methods,
+            headers,
+            max_age: config.max_age,
+        }
+    }
+
+    /// Returns the value of the Access-Control-Allow-Origin header for
+    /// requests from origin, if it is allowed
+    fn allow_origin(&self, origin: &HeaderValue) -> Option<HeaderValue> {
+        if self.any_origin {
+            return Some(HeaderValue::from_static("*"));
+        }
+        let lowercase = origin.to_str().ok()?.to_ascii_lowercase();
+        Some(origin.clone()).filter(|_| self.origins.contains(&lowercase))
+    }
+
+    /// Checks the method and headers a preflight request asks for
+    fn allows_request(&self, headers: &HeaderMap) -> bool {
+        let method = headers
+            .get(ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|method| Method::from_bytes(method.as_bytes()).ok());
+        let is_allowed_header = |name: &str| {
+            let name = name.trim();
+            name.is_empty()
+                || self
+                    .headers
+                    .iter()
+                    .any(|header| header.as_str().eq_ignore_ascii_case(name))
+        };
+        let requested_headers = headers
+            .get(ACCESS_CONTROL_REQUEST_HEADERS)
+            .map_or(Some(""), |value| value.to_str().ok());
+
+        match (method, requested_headers) {
+            (Some(method), Some(requested)) => {
+                self.methods.contains(&method) && requested.split(',').all(is_allowed_header)
+            }
+            _ => false,
+        }
+    }
+
+    /// Builds the response to an allowed preflight request
+    fn preflight_response(&self, allow_origin: HeaderValue) -> HttpResponse {
+        let mut response = HttpResponse::NoContent();
+        response
+            .header(ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin)
+            .header(ACCESS_CONTROL_ALLOW_METHODS, join(&self.methods))
+            .header(ACCESS_CONTROL_ALLOW_HEADERS, join(&self.headers))
+            .header(ACCESS_CONTROL_MAX_AGE, self.max_age.to_string());
+        if !self.any_origin {
+            response.header(VARY, "Origin");
+        }
+        response.finish()
+    }
+}
+
+/// Answers preflight requests to the API, responding with 403 Forbidden to
+/// the ones asking for an origin, method or header that is not allowed.
+/// Otherwise returns the request and, for allowed cross-origin API requests,
+/// the value of the Access-Control-Allow-Origin header of its response.
+pub fn preflight(
+    cors: &Cors,
+    req: ServiceRequest,
+) -> Result<(ServiceRequest, Option<HeaderValue>), ServiceResponse> {
+    if cors.origins.is_empty() || !is_api(&req) {
+        return Ok((req, None));
+    }
+    let allow_origin = match req.headers().get(ORIGIN) {
+        Some(origin) => cors.allow_origin(origin),
+        None => return Ok((req, None)),
+    };
+
+    let is_preflight = req.method() == Method::OPTIONS
+        && req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD);
+    if !is_preflight {
+        return Ok((req, allow_origin));
+    }
+    match allow_origin {
+        Some(allow_origin) if cors.allows_request(req.headers()) => {
+            let response = cors.preflight_response(allow_origin);
+            Err(req.into_response(response))
+        }
+        _ => {
+            debug!("Rejected CORS preflight request to {}", req.path());
+            Err(req.error_response(Error::forbidden()))
+        }
+    }
+}
+
+/// Adds the CORS headers to the response of an allowed cross-origin request
+pub fn allow<B>(cors: &Cors, allow_origin: HeaderValue, response: &mut ServiceResponse<B>) {
+    let headers = response.headers_mut();
+    headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+    headers.insert(
+        ACCESS_CONTROL_EXPOSE_HEADERS,
+        HeaderValue::from_static("Location"),
+    );
+    if !cors.any_origin {
+        headers.append(VARY, HeaderValue::from_static("Origin"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn cors(origins: &[&str]) -> Cors {
+        Cors::new(&CorsConfig {
+            origins: origins.iter().map(|origin| String::from(*origin)).collect(),
+            methods: vec![
+                String::from("GET"),
+                String::from("POST"),
+                String::from("no method"),
+            ],
+            ..CorsConfig::default()
+        })
+    }
+
+    fn request(method: Method, path: &str, headers: &[(HeaderName, &str)]) -> ServiceRequest {
+        headers
+            .iter()
+            .fold(
+                TestRequest::with_uri(path).method(method),
+                |req, (name, value)| req.header(name.clone(), *value),
+            )
+            .to_srv_request()
+    }
+
+    fn preflight_request(origin: &str, method: &str, headers: Option<&str>) -> ServiceRequest {
+        let mut request_headers = vec![(ORIGIN, origin), (ACCESS_CONTROL_REQUEST_METHOD, method)];
+        if let Some(headers) = headers {
+            request_headers.push((ACCESS_CONTROL_REQUEST_HEADERS, headers));
+        }
+        request(Method::OPTIONS, "/api/link", &request_headers)
+    }
+
+    fn header<B>(response: &ServiceResponse<B>, name: HeaderName) -> Option<&str> {
+        response
+            .headers()
+            .get(name)
+            .map(|value| value.to_str().unwrap())
+    }
+
+    #[test]
+    fn matches_origins() {
+        let cors = cors(&["https://Dashboard.example.com/"]);
+        let origin = |origin| cors.allow_origin(&HeaderValue::from_static(origin));
+        assert_eq!(
+            origin("https://dashboard.example.com"),
+            Some(HeaderValue::from_static("https://dashboard.example.com"))
+        );
+        // the origin is sent back as it was requested
+        assert_eq!(
+            origin("HTTPS://DASHBOARD.example.com"),
+            Some(HeaderValue::from_static("HTTPS://DASHBOARD.example.com"))
+        );
+        for other in &[
+            "http://dashboard.example.com",
+            "https://dashboard.example.com:8443",
+            "https://evil-dashboard.example.com",
+            "https://dashboard.example.com.evil.org",
+            "null",
+        ] {
+            assert_eq!(origin(other), None, "{}", other);
+        }
+
+        let any = self::cors(&["*"]);
+        assert_eq!(
+            any.allow_origin(&HeaderValue::from_static("https://a.org")),
+            Some(HeaderValue::from_static("*"))
+        );
+    }
+
+    #[test]
+    fn checks_requested_methods_and_headers() {
+        let cors = cors(&["*"]);
+        assert_eq!(cors.methods, vec![Method::GET, Method::POST]);
+        let allows = |method, headers: Option<&str>| {
+            cors.allows_request(preflight_request("https://a.org", method, headers).headers())
+        };
+        assert!(allows("POST", None));
+        assert!(allows("POST", Some("content-type, AUTHORIZATION")));
+        assert!(allows("GET", Some("")));
+        assert!(!allows("DELETE", None));
+        assert!(!allows("post", None));
+        assert!(!allows("POST", Some("Content-Type, X-Custom")));
+    }
+
+    #[test]
+    fn answers_preflight_requests() {
+        let cors = cors(&["https://a.org"]);
+
+        let response = preflight(&cors, preflight_request("https://a.org", "POST", None))
+            .err()
+            .unwrap();
+        assert_eq!(response.status(), 204);
+        assert_eq!(
+            header(&response, ACCESS_CONTROL_ALLOW_ORIGIN),
+            Some("https://a.org")
+        );
+        assert_eq!(
+            header(&response, ACCESS_CONTROL_ALLOW_METHODS),
+            Some("GET, POST")
+        );
+        assert_eq!(
+            header(&response, ACCESS_CONTROL_ALLOW_HEADERS),
+            Some("authorization, content-type")
+        );
+        assert_eq!(header(&response, ACCESS_CONTROL_MAX_AGE), Some("86400"));
+        assert_eq!(header(&response, VARY), Some("Origin"));
+
+        for req in [
+            preflight_request("https://b.org", "POST", None),
+            preflight_request("https://a.org", "DELETE", None),
+            preflight_request("https://a.org", "POST", Some("X-Custom")),
+        ] {
+            let response = preflight(&cors, req).err().unwrap();
+            assert_eq!(response.status(), 403);
+            assert_eq!(header(&response, ACCESS_CONTROL_ALLOW_ORIGIN), None);
+        }
+    }
+
+    #[test]
+    fn only_handles_api_requests() {
+        let cors = cors(&["https://a.org"]);
+        let allowed = |method, path, origin| {
+            let req = request(method, path, &[(ORIGIN, origin)]);
+            preflight(&cors, req).ok().unwrap().1
+        };
+        let origin = Some(HeaderValue::from_static("https://a.org"));
+
+        assert_eq!(
+            allowed(Method::GET, "/api/link/abc", "https://a.org"),
+            origin
+        );
+        assert_eq!(allowed(Method::POST, "/", "https://a.org"), origin);
+        assert_eq!(allowed(Method::GET, "/api/link/abc", "https://b.org"), None);
+        for path in &["/", "/abc", "/abc+", "/static/style.css", "/apix"] {
+            assert_eq!(
+                allowed(Method::GET, path, "https://a.org"),
+                None,
+                "{}",
+                path
+            );
+        }
+
+        // preflight requests to other routes reach the handlers
+        let req = request(
+            Method::OPTIONS,
+            "/abc",
+            &[
+                (ORIGIN, "https://b.org"),
+                (ACCESS_CONTROL_REQUEST_METHOD, "GET"),
+            ],
+        );
+        assert!(preflight(&cors, req).is_ok());
+        // and so does everything if CORS is disabled
+        let req = preflight_request("https://a.org", "POST", None);
+        assert!(preflight(&self::cors(&[]), req).is_ok());
+    }
+
+    #[test]
+    fn adds_headers_to_responses() {
+        let cors = cors(&["https://a.org"]);
+        let req = request(Method::GET, "/api/link/abc", &[]);
+        let mut response = req.into_response(HttpResponse::Ok().header(VARY, "Accept").finish());
+        allow(
+            &cors,
+            HeaderValue::from_static("https://a.org"),
+            &mut response,
+        );
+
+        assert_eq!(
+            header(&response, ACCESS_CONTROL_ALLOW_ORIGIN),
+            Some("https://a.org")
+        );
+        assert_eq!(
+            header(&response, ACCESS_CONTROL_EXPOSE_HEADERS),
+            Some("Location")
+        );
+        // keeps the Vary header of the handler
+        let mut vary = response.headers().get_all(VARY).collect::<Vec<_>>();
+        vary.sort();
+        assert_eq!(vary, vec!["Accept", "Origin"]);
+    }
+}
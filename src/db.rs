@@ -1,10 +1,17 @@
-use actix_web::{web, Error as AWError};
+// failure_derive implements its traits inside of a const block
+#![allow(non_local_definitions)]
+
+use actix_web::error::BlockingError;
 use failure::Error;
 use failure_derive::Fail;
 use futures::{Future, TryFutureExt};
 use r2d2_sqlite::SqliteConnectionManager;
-use rusqlite::NO_PARAMS;
+use rusqlite::{params, OptionalExtension, Row, ToSql, NO_PARAMS};
 
+use super::health::{CheckResult, Health};
+use super::metadata::Metadata;
+use super::metrics;
+use super::redirect::{RedirectMode, UnfurlMode};
 use super::short_code::{random_uuid, ShortCode};
 
 /// generalized Result type using failure to wrap different error types
@@ -17,17 +24,39 @@ pub enum DBError {
     #[fail(display = "The loaded database has not been initialized.")]
     InvalidSchema,
 
+    #[fail(display = "The given API key is not assigned to any user.")]
+    InvalidApiKey,
+
+    #[fail(display = "The requested entry does not exist.")]
+    NotFound,
+
+    #[fail(display = "The user is not allowed to modify this entry.")]
+    PermissionDenied,
+
+    #[fail(display = "An entry with the same name already exists.")]
+    Conflict,
+
+    #[fail(display = "The database query has been canceled.")]
+    Canceled,
+
+    #[fail(display = "The database is unavailable: {}", _0)]
+    Unavailable(String),
+
     #[fail(display = "Database error: {} ({})", msg, src)]
     SqliteError { msg: String, src: rusqlite::Error },
 }
 
 /// Result type to wrap database return values,
-/// can be String, Number(i64) or None
+/// can be String, Number(i64), Link, Links, Stored, StoredEach or None
 #[derive(Debug)]
 pub enum DBValue {
     String(String),
     Number(i64),
     // Bool(bool),
+    Link(Box<Link>),
+    Links(Vec<Link>, Option<String>),        // links, next cursor
+    Stored(Vec<(String, bool)>),             // [(short_code, is_new)]
+    StoredEach(Vec<Option<(String, bool)>>), // [(short_code, is_new) or None if the name is taken]
     None,
 }
 
@@ -35,12 +64,80 @@ pub type Pool = r2d2::Pool<SqliteConnectionManager>;
 pub type Connection = r2d2::PooledConnection<SqliteConnectionManager>;
 
 /// Describes the expected structure for posting new URLs
-#[derive(serde::Deserialize)]
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
 pub struct UrlPostData {
+    #[serde(flatten)]
+    pub link: LinkPostData,
+    pub key: String,
+}
+
+/// Describes the expected structure for posting new URLs via the API,
+/// like UrlPostData but without the key, which is sent separately
+#[derive(Clone, serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
+pub struct LinkPostData {
     pub url: String,
     pub title: Option<String>,
     pub description: Option<String>,
-    pub key: String,
+    pub redirect: Option<RedirectMode>,
+    pub image: Option<String>,
+    pub unfurl: Option<UnfurlMode>,
+    pub passthrough: Option<bool>,
+    /// the name of vanity and template links, see template.rs
+    pub name: Option<String>,
+    /// the configured domain whose namespace the name belongs to
+    pub domain: Option<String>,
+}
+
+/// Describes the expected structure for updating URLs,
+/// fields that are not set stay untouched and nullable fields
+/// are reset to their default when set to null
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
+pub struct UrlPatchData {
+    pub url: Option<String>,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub redirect: Option<Option<RedirectMode>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub image: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub unfurl: Option<Option<UnfurlMode>>,
+    pub passthrough: Option<bool>,
+}
+
+/// Deserializes a field that is present, even if null, into Some
+fn nullable<'de, T, D>(de: D) -> std::result::Result<Option<Option<T>>, D::Error>
+where
+    T: serde::Deserialize<'de>,
+    D: serde::Deserializer<'de>,
+{
+    serde::Deserialize::deserialize(de).map(Some)
+}
+
+/// A stored URL with all its metadata
+#[derive(Debug)]
+pub struct Link {
+    pub code: String,
+    pub url: String,
+    pub title: String,
+    pub description: String,
+    pub created_at: String,
+    pub visits: i64,
+    pub user_id: i64,
+    pub redirect: Option<RedirectMode>,
+    pub image: Option<String>,
+    pub unfurl: Option<UnfurlMode>,
+    pub canonical_url: Option<String>,
+    /// result of the last health check, none if never checked
+    pub health: Option<Health>,
+    /// set if the target domain is not allowed by the domain policy
+    pub blocked_at: Option<String>,
+    /// forwards path suffixes and query strings to the target
+    pub passthrough: bool,
+    /// set for vanity and template links, see template.rs
+    pub name: Option<String>,
+    /// the configured domain of the link, none for base_url
+    pub domain: Option<String>,
 }
 
 /// Possible database queries, used with db::query
@@ -48,9 +145,63 @@ pub enum Queries {
     NeedsInit,
     CountUsers,
     InitDB,
-    CreateUser(i64, bool),    // rate_limit, is_admin
-    GetURL(String),           // short_code
-    StoreNewURL(UrlPostData), // api_key, url, title?, description?
+    CreateUser(i64, bool),                         // rate_limit, is_admin
+    GetURL(String),                                // short_code
+    GetLink(String),                               // short_code
+    ListLinks(String, Option<String>, u32),        // api_key, cursor?, limit
+    StoreNewURL(UrlPostData, bool),                // api_key and link data, deduplicate
+    StoreNewURLs(String, Vec<LinkPostData>, bool), // api_key, [link data], deduplicate
+    UpdateLink(String, String, UrlPatchData),      // api_key, short_code, link data
+    DeleteLink(String, String),                    // api_key, short_code
+    StoreMetadata(String, Metadata),               // short_code, fetched metadata
+    LinksToCheck(u32, u64),                        // limit, seconds since last check
+    StoreHealth(String, CheckResult),              // short_code, check result
+    ListBrokenLinks(String, Option<String>, u32),  // api_key, cursor?, limit
+    GetUserId(String),                             // api_key
+    CheckAdmin(String),                            // api_key
+    FindNamed(Option<String>, Vec<String>),        // domain, [name]
+    AllLinks,
+    SetBlocked(Vec<(String, bool)>), // [(short_code, is_blocked)]
+    DatabaseSize,
+    CheckWritable,
+}
+
+/// Schema changes on top of the initial schema, applied in order.
+/// The number of applied migrations is stored in the user_version pragma.
+const MIGRATIONS: &[&str] = &[
+    "ALTER TABLE URLs ADD COLUMN deleted_at DATETIME;",
+    "ALTER TABLE URLs ADD COLUMN redirect_mode TEXT;",
+    "ALTER TABLE URLs ADD COLUMN image TEXT; ALTER TABLE URLs ADD COLUMN unfurl TEXT;",
+    "ALTER TABLE URLs ADD COLUMN canonical_url TEXT;",
+    "ALTER TABLE URLs ADD COLUMN checked_at DATETIME;
+     ALTER TABLE URLs ADD COLUMN check_status INTEGER;
+     ALTER TABLE URLs ADD COLUMN check_url TEXT;
+     ALTER TABLE URLs ADD COLUMN check_error TEXT;
+     ALTER TABLE URLs ADD COLUMN check_failures INTEGER DEFAULT 0;",
+    "ALTER TABLE URLs ADD COLUMN blocked_at DATETIME;",
+    "CREATE INDEX IF NOT EXISTS idx_user_url ON URLs(user_id, url);",
+    "ALTER TABLE URLs ADD COLUMN passthrough INTEGER DEFAULT 0;",
+    "ALTER TABLE URLs ADD COLUMN name TEXT;
+     CREATE UNIQUE INDEX IF NOT EXISTS idx_name ON URLs(name) WHERE deleted_at IS NULL;",
+    "ALTER TABLE URLs ADD COLUMN domain TEXT;
+     DROP INDEX IF EXISTS idx_name;
+     CREATE UNIQUE INDEX IF NOT EXISTS idx_domain_name ON URLs(IFNULL(domain, ''), name)
+       WHERE deleted_at IS NULL;",
+];
+
+/// Columns selected to build a Link, see link_from_row
+const LINK_COLUMNS: &str = "rowid, url, title, description,
+    strftime('%Y-%m-%dT%H:%M:%SZ', created_at), visits, user_id, redirect_mode,
+    image, unfurl, canonical_url, strftime('%Y-%m-%dT%H:%M:%SZ', checked_at),
+    check_status, check_url, check_error, check_failures,
+    strftime('%Y-%m-%dT%H:%M:%SZ', blocked_at), passthrough, name, domain";
+
+/// Wraps rusqlite errors into DBError::SqliteError with a descriptive message
+fn sqlite_error(msg: &str) -> impl FnOnce(rusqlite::Error) -> Error + '_ {
+    move |src| {
+        let msg = msg.to_owned();
+        Error::from(DBError::SqliteError { msg, src })
+    }
 }
 
 /// Queries the database schema and creates a descriptive string like
@@ -86,12 +237,27 @@ fn get_database_schema(conn: &Connection) -> Result<String> {
 }
 
 /// compares a hard coded expected database schema with the actual one
+/// and checks that all migrations are applied
 fn check_database_schema(conn: Connection) -> Result {
     // TODO: is that really a good way to check the schema?
     let expected_schema = String::from(
-        "URLs|created_at
+        "URLs|blocked_at
+URLs|canonical_url
+URLs|check_error
+URLs|check_failures
+URLs|check_status
+URLs|check_url
+URLs|checked_at
+URLs|created_at
+URLs|deleted_at
 URLs|description
+URLs|domain
+URLs|image
+URLs|name
+URLs|passthrough
+URLs|redirect_mode
 URLs|title
+URLs|unfurl
 URLs|url
 URLs|user_id
 URLs|visits
@@ -101,8 +267,12 @@ Users|rate_limit
 Users|rowid",
     );
     let schema = get_database_schema(&conn)?;
+    // migrations like new indexes do not change the columns
+    let version: i64 = conn
+        .query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0))
+        .map_err(sqlite_error("Could not read schema version."))?;
 
-    if schema == expected_schema {
+    if schema == expected_schema && version == MIGRATIONS.len() as i64 {
         debug!("Schema validated!");
         Ok(DBValue::None)
     } else {
@@ -111,7 +281,8 @@ Users|rowid",
     }
 }
 
-/// Initializes a new SQlite database with the default schema.
+/// Initializes a new SQlite database with the default schema
+/// and brings it up to date by applying all migrations.
 fn init_database(conn: Connection) -> Result {
     conn.execute_batch(
         "
@@ -135,11 +306,33 @@ fn init_database(conn: Connection) -> Result {
         );
         COMMIT;",
     )
-    .map(|_| DBValue::None)
-    .map_err(|src| {
-        let msg = "Failed to init DB!".to_owned();
-        Error::from(DBError::SqliteError { msg, src })
-    })
+    .map_err(sqlite_error("Failed to init DB!"))?;
+
+    migrate_database(&conn)
+}
+
+/// Applies all migrations that are not yet reflected in the user_version pragma
+fn migrate_database(conn: &Connection) -> Result {
+    let version: i64 = conn
+        .query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0))
+        .map_err(sqlite_error("Could not read schema version."))?;
+
+    for (n, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        debug!("Applying database migration {}...", n + 1);
+        let result = conn.execute_batch(&format!(
+            "BEGIN; {} PRAGMA user_version = {}; COMMIT;",
+            migration,
+            n + 1
+        ));
+        // a failed statement leaves the transaction open on the pooled connection
+        if result.is_err() && !conn.is_autocommit() {
+            conn.execute_batch("ROLLBACK;")
+                .map_err(sqlite_error("Failed to roll back migration!"))?;
+        }
+        result.map_err(sqlite_error("Failed to migrate DB!"))?;
+    }
+
+    Ok(DBValue::None)
 }
 
 /// counts entries in Users table and returns that count as DBValue::Number
@@ -147,11 +340,39 @@ fn count_users(conn: Connection) -> Result {
     conn.query_row("SELECT COUNT(rowid) FROM USERS", NO_PARAMS, |row| {
         row.get(0)
     })
-    .map(|v| DBValue::Number(v))
-    .map_err(|src| {
-        let msg = "Could not check users.".to_owned();
-        Error::from(DBError::SqliteError { msg, src })
-    })
+    .map(DBValue::Number)
+    .map_err(sqlite_error("Could not check users."))
+}
+
+/// Returns the size of the database file in bytes, without the WAL
+fn database_size(conn: Connection) -> Result {
+    conn.query_row(
+        "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()",
+        NO_PARAMS,
+        |row| row.get(0),
+    )
+    .map(DBValue::Number)
+    .map_err(sqlite_error("Could not get database size."))
+}
+
+/// Checks that files can be written next to the database file, by writing
+/// and removing a probe file. In-memory databases are always writable.
+fn check_writable(conn: Connection) -> Result {
+    let path: String = conn
+        .query_row(
+            "SELECT file FROM pragma_database_list WHERE name = 'main'",
+            NO_PARAMS,
+            |row| row.get(0),
+        )
+        .map_err(sqlite_error("Could not find database file."))?;
+    if path.is_empty() {
+        return Ok(DBValue::None);
+    }
+
+    let probe = format!("{}-probe", path);
+    std::fs::write(&probe, b"k0r")?;
+    std::fs::remove_file(&probe)?;
+    Ok(DBValue::None)
 }
 
 /// Creates a new user entry with random API key
@@ -164,67 +385,901 @@ fn create_user(conn: Connection, rate_limit: i64, is_admin: bool) -> Result {
         &[&new_key, &rate_limit.to_string(), &is_admin.to_string()],
     )
     .map(|_| DBValue::String(new_key))
-    .map_err(|src| {
-        let msg = "Could not create user.".to_owned();
-        Error::from(DBError::SqliteError { msg, src })
+    .map_err(sqlite_error("Could not create user."))
+}
+
+/// Looks up the user assigned to api_key and returns its id and admin flag
+fn get_user(conn: &Connection, api_key: &str) -> Result<(i64, bool)> {
+    conn.query_row(
+        "SELECT rowid, is_admin FROM Users WHERE api_key = ?",
+        &[api_key],
+        |row| Ok((row.get(0)?, row.get::<_, i64>(1)? != 0)),
+    )
+    .optional()
+    .map_err(sqlite_error("Could not retrieve user."))?
+    .ok_or_else(|| Error::from(DBError::InvalidApiKey))
+}
+
+/// Translates a short_code to its row id, unparsable codes cannot exist
+fn row_id(short_code: &str) -> Result<i64> {
+    ShortCode::from_code(short_code)
+        .map(|code| code.n as i64)
+        .map_err(|_| Error::from(DBError::NotFound))
+}
+
+/// Builds a Link from a row selected with LINK_COLUMNS
+fn link_from_row(row: &Row) -> rusqlite::Result<Link> {
+    Ok(Link {
+        code: ShortCode::new(row.get::<_, i64>(0)? as usize).code,
+        url: row.get(1)?,
+        title: row.get::<_, Option<String>>(2)?.unwrap_or_default(),
+        description: row.get::<_, Option<String>>(3)?.unwrap_or_default(),
+        created_at: row.get::<_, Option<String>>(4)?.unwrap_or_default(),
+        visits: row.get(5)?,
+        user_id: row.get(6)?,
+        redirect: row
+            .get::<_, Option<String>>(7)?
+            .and_then(|mode| mode.parse().ok()),
+        image: row.get(8)?,
+        unfurl: row
+            .get::<_, Option<String>>(9)?
+            .and_then(|mode| mode.parse().ok()),
+        canonical_url: row.get(10)?,
+        health: match row.get::<_, Option<String>>(11)? {
+            Some(checked_at) => Some(Health {
+                status: row.get(12)?,
+                final_url: row.get(13)?,
+                error: row.get(14)?,
+                checked_at,
+                failures: row.get::<_, Option<i64>>(15)?.unwrap_or(0),
+            }),
+            None => None,
+        },
+        blocked_at: row.get(16)?,
+        passthrough: row.get::<_, Option<bool>>(17)?.unwrap_or(false),
+        name: row.get(18)?,
+        domain: row.get(19)?,
     })
 }
 
 /// Looks up an URL by translating the short_code to its ID
+/// and counts the lookup as visit, unless the link is blocked.
+/// Returns the link as DBValue::Link.
 /// short_code is simply the base36 version of the table id
 fn get_url(conn: Connection, short_code: &str) -> Result {
-    let row_id = ShortCode::from_code(short_code)?.n;
+    let link = find_link(&conn, short_code)?;
+    if link.blocked_at.is_some() {
+        return Ok(DBValue::Link(Box::new(link)));
+    }
 
+    conn.execute(
+        "UPDATE URLs SET visits = visits + 1 WHERE rowid = ?",
+        [row_id(short_code)?],
+    )
+    .map_err(sqlite_error("Could not count visit"))?;
+
+    Ok(DBValue::Link(Box::new(link)))
+}
+
+/// Looks up a link with all its metadata, without counting it as visit
+fn find_link(conn: &Connection, short_code: &str) -> Result<Link> {
+    let row_id = row_id(short_code)?;
+
+    conn.query_row(
+        &format!(
+            "SELECT {} FROM URLs WHERE rowid = ? AND deleted_at IS NULL",
+            LINK_COLUMNS
+        ),
+        [row_id],
+        link_from_row,
+    )
+    .optional()
+    .map_err(sqlite_error("Could not retrieve link"))?
+    .ok_or_else(|| Error::from(DBError::NotFound))
+}
+
+/// Looks up a link and makes sure that api_key belongs to its owner or an admin
+fn find_owned_link(conn: &Connection, api_key: &str, short_code: &str) -> Result<Link> {
+    let (user_id, is_admin) = get_user(conn, api_key)?;
+    let link = find_link(conn, short_code)?;
+
+    if link.user_id == user_id || is_admin {
+        Ok(link)
+    } else {
+        Err(Error::from(DBError::PermissionDenied))
+    }
+}
+
+/// Returns a link with all its metadata as DBValue::Link
+fn get_link(conn: Connection, short_code: &str) -> Result {
+    find_link(&conn, short_code).map(|link| DBValue::Link(Box::new(link)))
+}
+
+/// Lists the links of the user assigned to api_key, ordered by creation.
+/// The cursor is the short code of the last link of the previous page.
+fn list_links(conn: Connection, api_key: &str, cursor: Option<&str>, limit: u32) -> Result {
+    let (user_id, _) = get_user(&conn, api_key)?;
+    let after = cursor.map(row_id).transpose()?.unwrap_or(0);
+
+    let mut stmt = conn
+        .prepare(&format!(
+            "SELECT {} FROM URLs
+             WHERE user_id = ? AND rowid > ? AND deleted_at IS NULL
+             ORDER BY rowid
+             LIMIT ?",
+            LINK_COLUMNS
+        ))
+        .map_err(sqlite_error("Could not list links"))?;
+
+    // one more than requested tells if there is a next page
+    let mut links = stmt
+        .query_map(params![user_id, after, limit + 1], link_from_row)
+        .and_then(|rows| rows.collect::<rusqlite::Result<Vec<Link>>>())
+        .map_err(sqlite_error("Could not list links"))?;
+
+    let next_cursor = if links.len() > limit as usize {
+        links.truncate(limit as usize);
+        links.last().map(|link| link.code.clone())
+    } else {
+        None
+    };
+
+    Ok(DBValue::Links(links, next_cursor))
+}
+
+/// Checks if the first segment of name is the short code of an existing
+/// link, which the name would take over as names take precedence
+fn shadows_short_code(conn: &rusqlite::Connection, name: &str) -> Result<bool> {
+    let first = name.split('/').next().unwrap_or_default();
+    let row_id = match ShortCode::from_code(first) {
+        Ok(code) => code.n as i64,
+        Err(_) => return Ok(false),
+    };
     conn.query_row(
-        "SELECT url FROM URLs WHERE rowid = ?",
-        &[row_id as i64],
+        "SELECT EXISTS(SELECT 1 FROM URLs WHERE rowid = ? AND deleted_at IS NULL)",
+        [row_id],
         |row| row.get(0),
     )
-    .map(DBValue::String)
-    .map_err(|src| {
-        let msg = "Could not retrieve URL".to_owned();
-        Error::from(DBError::SqliteError { msg, src })
-    })
+    .map_err(sqlite_error("Could not look up short codes."))
 }
 
-/// Stores a new URL if api_key is assigned to a valid user
-fn store_url(conn: Connection, data: &UrlPostData) -> Result {
-    let user_id: i64 = conn.query_row(
-        "SELECT rowid FROM Users WHERE api_key = ?",
-        &[&data.key],
+/// Checks if short_code is the first segment of a name, including the name
+/// of its own link, so that short codes and names never overlap
+fn is_named(conn: &rusqlite::Connection, short_code: &str) -> Result<bool> {
+    conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM URLs
+           WHERE deleted_at IS NULL AND (name = ?1 OR name LIKE ?1 || '/%'))",
+        params![short_code],
         |row| row.get(0),
-    )?;
-    let _ = conn.execute_named(
-        "INSERT INTO URLs VALUES(:url, 0, :title, :description, DATETIME('now'), :user_id)",
-        &[
-            (":url", &data.url),
-            (":title", data.title.as_ref().unwrap_or(&String::from(""))),
-            (
-                ":description",
-                data.description.as_ref().unwrap_or(&String::from("")),
-            ),
-            (":user_id", &(user_id.to_string())),
-        ],
-    )?;
+    )
+    .map_err(sqlite_error("Could not look up names."))
+}
+
+/// Inserts a new URL for user_id and returns its short code. Names that
+/// start with the short code of an existing link are rejected, and short
+/// codes that are the start of an existing name are skipped, so a short
+/// code can be looked up without looking for names first.
+fn insert_url(conn: &rusqlite::Connection, user_id: i64, data: &LinkPostData) -> Result<String> {
+    if let Some(name) = &data.name {
+        if shadows_short_code(conn, name)? {
+            return Err(Error::from(DBError::Conflict));
+        }
+    }
+
+    loop {
+        let short_code = insert_row(conn, user_id, data)?;
+        let row_id = conn.last_insert_rowid();
+        if !is_named(conn, &short_code)? {
+            return Ok(short_code);
+        }
+        // deleted rows keep their short code from being handed out again
+        debug!("Skipping short code {}, it is taken by a name", short_code);
+        conn.execute(
+            "UPDATE URLs SET deleted_at = DATETIME('now') WHERE rowid = ?",
+            [row_id],
+        )
+        .map_err(sqlite_error("Could not skip short code"))?;
+    }
+}
+
+/// Inserts a row for a new URL of user_id and returns its short code
+fn insert_row(conn: &rusqlite::Connection, user_id: i64, data: &LinkPostData) -> Result<String> {
+    let _ = conn
+        .execute_named(
+            "INSERT INTO URLs (url, visits, title, description, created_at, user_id, redirect_mode,
+                           image, unfurl, passthrough, name, domain)
+         VALUES(:url, 0, :title, :description, DATETIME('now'), :user_id, :redirect,
+                :image, :unfurl, :passthrough, :name, :domain)",
+            &[
+                (":url", &data.url),
+                (":title", &data.title.as_deref().unwrap_or("")),
+                (":description", &data.description.as_deref().unwrap_or("")),
+                (":user_id", &user_id),
+                (":redirect", &data.redirect.map(RedirectMode::as_str)),
+                (":image", &data.image),
+                (":unfurl", &data.unfurl.map(UnfurlMode::as_str)),
+                (":passthrough", &data.passthrough.unwrap_or(false)),
+                (":name", &data.name),
+                (":domain", &data.domain),
+            ],
+        )
+        .map_err(|err| match err {
+            rusqlite::Error::SqliteFailure(failure, _)
+                if failure.code == rusqlite::ErrorCode::ConstraintViolation =>
+            {
+                Error::from(DBError::Conflict)
+            }
+            err => Error::from(err),
+        })?;
     // TODO: In case a plain [0-9a-z] string will be included into
     // IGNORED_SHORT_CODES, this function should work around such IDs as well.
     let short_code = ShortCode::new(conn.last_insert_rowid() as usize).code;
-    Ok(DBValue::String(short_code))
+    Ok(short_code)
+}
+
+/// Looks up the oldest unnamed link of user_id on the same domain as data
+/// pointing to the same url and returns its short code
+fn find_duplicate(
+    conn: &rusqlite::Connection,
+    user_id: i64,
+    data: &LinkPostData,
+) -> Result<Option<String>> {
+    conn.query_row(
+        "SELECT rowid FROM URLs
+         WHERE user_id = ? AND url = ? AND domain IS ? AND name IS NULL
+           AND deleted_at IS NULL
+         ORDER BY rowid LIMIT 1",
+        params![user_id, data.url, data.domain],
+        |row| row.get::<_, i64>(0),
+    )
+    .optional()
+    .map(|rowid| rowid.map(|rowid| ShortCode::new(rowid as usize).code))
+    .map_err(sqlite_error("Could not look up existing links."))
+}
+
+/// Returns the links on domain with one of the given names
+fn find_named(conn: Connection, domain: Option<&str>, names: &[String]) -> Result {
+    if names.is_empty() {
+        return Ok(DBValue::Links(Vec::new(), None));
+    }
+    let mut stmt = conn
+        .prepare(&format!(
+            "SELECT {} FROM URLs WHERE deleted_at IS NULL AND domain IS ? AND name IN ({})",
+            LINK_COLUMNS,
+            vec!["?"; names.len()].join(", ")
+        ))
+        .map_err(sqlite_error("Could not find named links"))?;
+
+    let params = std::iter::once(&domain as &dyn ToSql)
+        .chain(names.iter().map(|name| name as &dyn ToSql))
+        .collect::<Vec<&dyn ToSql>>();
+    let links = stmt
+        .query_map(params, link_from_row)
+        .and_then(|rows| rows.collect::<rusqlite::Result<Vec<Link>>>())
+        .map_err(sqlite_error("Could not find named links"))?;
+
+    Ok(DBValue::Links(links, None))
+}
+
+/// Inserts a new URL for user_id, or with deduplicate returns an existing link
+/// of user_id to the same URL. Returns the short code and whether it is new.
+fn store_link(
+    conn: &rusqlite::Connection,
+    user_id: i64,
+    data: &LinkPostData,
+    deduplicate: bool,
+) -> Result<(String, bool)> {
+    // named links are reached via their name, not their target
+    if deduplicate && data.name.is_none() {
+        if let Some(short_code) = find_duplicate(conn, user_id, data)? {
+            return Ok((short_code, false));
+        }
+    }
+    insert_url(conn, user_id, data).map(|short_code| (short_code, true))
+}
+
+/// Stores a new URL if api_key is assigned to a valid user,
+/// see store_link for deduplicate
+fn store_url(conn: Connection, data: &UrlPostData, deduplicate: bool) -> Result {
+    let (user_id, _) = get_user(&conn, &data.key)?;
+    let stored = store_link(&conn, user_id, &data.link, deduplicate)?;
+    metrics::count_created(stored.1 as usize);
+    Ok(DBValue::Stored(vec![stored]))
+}
+
+/// Stores many URLs at once in a single transaction if api_key is assigned
+/// to a valid user and returns their short codes in the same order
+/// as DBValue::StoredEach, see store_link for deduplicate. Each link is
+/// stored in its own savepoint, so that a name conflict only fails its entry.
+fn store_urls(
+    mut conn: Connection,
+    api_key: &str,
+    data: &[LinkPostData],
+    deduplicate: bool,
+) -> Result {
+    let (user_id, _) = get_user(&conn, api_key)?;
+    let mut tx = conn
+        .transaction()
+        .map_err(sqlite_error("Could not start transaction"))?;
+
+    let mut stored = Vec::with_capacity(data.len());
+    for link in data {
+        let savepoint = tx
+            .savepoint()
+            .map_err(sqlite_error("Could not create savepoint"))?;
+        match store_link(&savepoint, user_id, link, deduplicate) {
+            Ok(link) => {
+                savepoint
+                    .commit()
+                    .map_err(sqlite_error("Could not release savepoint"))?;
+                stored.push(Some(link));
+            }
+            // dropping the savepoint rolls it back
+            Err(err) if matches!(err.downcast_ref(), Some(DBError::Conflict)) => stored.push(None),
+            Err(err) => return Err(err),
+        }
+    }
+
+    tx.commit()
+        .map_err(sqlite_error("Could not commit transaction"))?;
+    let created = stored.iter().flatten().filter(|(_, is_new)| *is_new);
+    metrics::count_created(created.count());
+    Ok(DBValue::StoredEach(stored))
+}
+
+/// Updates the given fields of a link owned by the user assigned to api_key
+/// and returns the updated link as DBValue::Link. A new url resets everything
+/// known about the old one, including a block, as the API checks it against
+/// the domain policy before.
+fn update_link(conn: Connection, api_key: &str, short_code: &str, data: &UrlPatchData) -> Result {
+    let link = find_owned_link(&conn, api_key, short_code)?;
+
+    conn.execute(
+        "UPDATE URLs SET
+           url = COALESCE(?, url),
+           title = COALESCE(?, title),
+           description = COALESCE(?, description),
+           redirect_mode = CASE WHEN ? THEN ? ELSE redirect_mode END,
+           image = CASE WHEN ? THEN ? ELSE image END,
+           unfurl = CASE WHEN ? THEN ? ELSE unfurl END,
+           passthrough = COALESCE(?, passthrough),
+           canonical_url = CASE WHEN ?1 IS NULL THEN canonical_url ELSE NULL END,
+           checked_at = CASE WHEN ?1 IS NULL THEN checked_at ELSE NULL END,
+           check_failures = CASE WHEN ?1 IS NULL THEN check_failures ELSE 0 END,
+           blocked_at = CASE WHEN ?1 IS NULL THEN blocked_at ELSE NULL END
+         WHERE rowid = ?",
+        params![
+            data.url,
+            data.title,
+            data.description,
+            data.redirect.is_some(),
+            data.redirect.flatten().map(RedirectMode::as_str),
+            data.image.is_some(),
+            data.image.clone().flatten(),
+            data.unfurl.is_some(),
+            data.unfurl.flatten().map(UnfurlMode::as_str),
+            data.passthrough,
+            row_id(&link.code)?
+        ],
+    )
+    .map_err(sqlite_error("Could not update link"))?;
+
+    find_link(&conn, short_code).map(|link| DBValue::Link(Box::new(link)))
+}
+
+/// Deletes a link owned by the user assigned to api_key.
+/// The row is kept and only marked as deleted, so that its short code
+/// will never be handed out again.
+fn delete_link(conn: Connection, api_key: &str, short_code: &str) -> Result {
+    let link = find_owned_link(&conn, api_key, short_code)?;
+
+    conn.execute(
+        "UPDATE URLs SET deleted_at = DATETIME('now') WHERE rowid = ?",
+        [row_id(&link.code)?],
+    )
+    .map(|_| DBValue::None)
+    .map_err(sqlite_error("Could not delete link"))
+}
+
+/// Fills in missing title and description of a link with fetched metadata
+/// and stores its canonical URL
+fn store_metadata(conn: Connection, short_code: &str, metadata: &Metadata) -> Result {
+    conn.execute(
+        "UPDATE URLs SET
+           title = CASE WHEN title IS NULL OR title = ''
+                   THEN COALESCE(?, title) ELSE title END,
+           description = CASE WHEN description IS NULL OR description = ''
+                         THEN COALESCE(?, description) ELSE description END,
+           canonical_url = COALESCE(?, canonical_url)
+         WHERE rowid = ?",
+        params![
+            metadata.title,
+            metadata.description,
+            metadata.canonical_url,
+            row_id(short_code)?
+        ],
+    )
+    .map(|_| DBValue::None)
+    .map_err(sqlite_error("Could not store metadata"))
+}
+
+/// Returns links that were never checked or not within the last
+/// recheck_after seconds as DBValue::Links, the least recently checked first
+fn links_to_check(conn: Connection, limit: u32, recheck_after: u64) -> Result {
+    let mut stmt = conn
+        .prepare(&format!(
+            "SELECT {} FROM URLs
+             WHERE deleted_at IS NULL AND (name IS NULL OR url NOT LIKE '%{{%}}%')
+               AND (checked_at IS NULL OR checked_at < DATETIME('now', ?))
+             ORDER BY checked_at IS NOT NULL, checked_at, rowid
+             LIMIT ?",
+            LINK_COLUMNS
+        ))
+        .map_err(sqlite_error("Could not list links to check"))?;
+
+    let links = stmt
+        .query_map(
+            params![format!("-{} seconds", recheck_after), limit],
+            link_from_row,
+        )
+        .and_then(|rows| rows.collect::<rusqlite::Result<Vec<Link>>>())
+        .map_err(sqlite_error("Could not list links to check"))?;
+
+    Ok(DBValue::Links(links, None))
+}
+
+/// Stores the result of a health check and counts failed checks in a row
+fn store_health(conn: Connection, short_code: &str, result: &CheckResult) -> Result {
+    conn.execute(
+        "UPDATE URLs SET
+           checked_at = DATETIME('now'),
+           check_status = ?,
+           check_url = ?,
+           check_error = ?,
+           check_failures = CASE WHEN ? THEN COALESCE(check_failures, 0) + 1 ELSE 0 END
+         WHERE rowid = ?",
+        params![
+            result.status,
+            result.final_url,
+            result.error,
+            result.is_broken(),
+            row_id(short_code)?
+        ],
+    )
+    .map(|_| DBValue::None)
+    .map_err(sqlite_error("Could not store health check"))
+}
+
+/// Lists links of all users whose last health check failed, ordered by
+/// creation, if api_key belongs to an admin. Paginated like list_links.
+fn list_broken_links(conn: Connection, api_key: &str, cursor: Option<&str>, limit: u32) -> Result {
+    let (_, is_admin) = get_user(&conn, api_key)?;
+    if !is_admin {
+        return Err(Error::from(DBError::PermissionDenied));
+    }
+    let after = cursor.map(row_id).transpose()?.unwrap_or(0);
+
+    let mut stmt = conn
+        .prepare(&format!(
+            "SELECT {} FROM URLs
+             WHERE check_failures > 0 AND rowid > ? AND deleted_at IS NULL
+             ORDER BY rowid
+             LIMIT ?",
+            LINK_COLUMNS
+        ))
+        .map_err(sqlite_error("Could not list broken links"))?;
+
+    // one more than requested tells if there is a next page
+    let mut links = stmt
+        .query_map(params![after, limit + 1], link_from_row)
+        .and_then(|rows| rows.collect::<rusqlite::Result<Vec<Link>>>())
+        .map_err(sqlite_error("Could not list broken links"))?;
+
+    let next_cursor = if links.len() > limit as usize {
+        links.truncate(limit as usize);
+        links.last().map(|link| link.code.clone())
+    } else {
+        None
+    };
+
+    Ok(DBValue::Links(links, next_cursor))
+}
+
+/// Returns the id of the user assigned to api_key as DBValue::Number
+fn get_user_id(conn: Connection, api_key: &str) -> Result {
+    get_user(&conn, api_key).map(|(user_id, _)| DBValue::Number(user_id))
+}
+
+/// Fails with DBError::PermissionDenied unless api_key belongs to an admin
+fn check_admin(conn: Connection, api_key: &str) -> Result {
+    match get_user(&conn, api_key)? {
+        (_, true) => Ok(DBValue::None),
+        (_, false) => Err(Error::from(DBError::PermissionDenied)),
+    }
+}
+
+/// Returns all links that are not deleted as DBValue::Links
+fn all_links(conn: Connection) -> Result {
+    let mut stmt = conn
+        .prepare(&format!(
+            "SELECT {} FROM URLs WHERE deleted_at IS NULL ORDER BY rowid",
+            LINK_COLUMNS
+        ))
+        .map_err(sqlite_error("Could not list links"))?;
+
+    let links = stmt
+        .query_map(NO_PARAMS, link_from_row)
+        .and_then(|rows| rows.collect::<rusqlite::Result<Vec<Link>>>())
+        .map_err(sqlite_error("Could not list links"))?;
+
+    Ok(DBValue::Links(links, None))
+}
+
+/// Blocks or unblocks the given links in a single transaction
+fn set_blocked(mut conn: Connection, links: &[(String, bool)]) -> Result {
+    let tx = conn
+        .transaction()
+        .map_err(sqlite_error("Could not start transaction"))?;
+
+    for (short_code, is_blocked) in links {
+        tx.execute(
+            "UPDATE URLs SET blocked_at = CASE WHEN ? THEN DATETIME('now') ELSE NULL END
+             WHERE rowid = ?",
+            params![is_blocked, row_id(short_code)?],
+        )
+        .map_err(sqlite_error("Could not block link"))?;
+    }
+
+    tx.commit()
+        .map_err(sqlite_error("Could not commit transaction"))?;
+    Ok(DBValue::None)
+}
+
+/// Takes a connection from pool, failing with DBError::Unavailable
+/// if none becomes available in time
+fn connect(pool: &Pool) -> Result<r2d2::PooledConnection<SqliteConnectionManager>> {
+    pool.get()
+        .map_err(|err| Error::from(DBError::Unavailable(err.to_string())))
+}
+
+/// Turns errors of a busy or locked database into DBError::Unavailable,
+/// they are temporary unlike other sqlite errors
+fn classify(err: Error) -> Error {
+    let src = match err.downcast_ref::<DBError>() {
+        Some(DBError::SqliteError { src, .. }) => Some(src),
+        _ => err.downcast_ref::<rusqlite::Error>(),
+    };
+    match src {
+        Some(rusqlite::Error::SqliteFailure(failure, _))
+            if failure.code == rusqlite::ErrorCode::DatabaseBusy
+                || failure.code == rusqlite::ErrorCode::DatabaseLocked =>
+        {
+            Error::from(DBError::Unavailable(failure.to_string()))
+        }
+        _ => err,
+    }
 }
 
 /// translates Queries to function calls and returns the result as Future
-pub fn query(
-    pool: &Pool,
-    query: Queries,
-) -> impl Future<Output = std::result::Result<DBValue, AWError>> {
+pub fn query(pool: &Pool, query: Queries) -> impl Future<Output = Result> {
     let pool = pool.clone();
-    web::block(move || match query {
-        Queries::NeedsInit => check_database_schema(pool.get()?),
-        Queries::CountUsers => count_users(pool.get()?),
-        Queries::InitDB => init_database(pool.get()?),
-        Queries::CreateUser(rate_limit, is_admin) => create_user(pool.get()?, rate_limit, is_admin),
-        Queries::GetURL(short_code) => get_url(pool.get()?, &short_code),
-        Queries::StoreNewURL(url_data) => store_url(pool.get()?, &url_data),
+    metrics::block(move || match query {
+        Queries::NeedsInit => check_database_schema(connect(&pool)?),
+        Queries::CountUsers => count_users(connect(&pool)?),
+        Queries::InitDB => init_database(connect(&pool)?),
+        Queries::CreateUser(rate_limit, is_admin) => {
+            create_user(connect(&pool)?, rate_limit, is_admin)
+        }
+        Queries::GetURL(short_code) => get_url(connect(&pool)?, &short_code),
+        Queries::GetLink(short_code) => get_link(connect(&pool)?, &short_code),
+        Queries::ListLinks(api_key, cursor, limit) => {
+            list_links(connect(&pool)?, &api_key, cursor.as_deref(), limit)
+        }
+        Queries::StoreNewURL(url_data, dedup) => store_url(connect(&pool)?, &url_data, dedup),
+        Queries::StoreNewURLs(api_key, url_data, dedup) => {
+            store_urls(connect(&pool)?, &api_key, &url_data, dedup)
+        }
+        Queries::UpdateLink(api_key, short_code, url_data) => {
+            update_link(connect(&pool)?, &api_key, &short_code, &url_data)
+        }
+        Queries::DeleteLink(api_key, short_code) => {
+            delete_link(connect(&pool)?, &api_key, &short_code)
+        }
+        Queries::StoreMetadata(short_code, metadata) => {
+            store_metadata(connect(&pool)?, &short_code, &metadata)
+        }
+        Queries::LinksToCheck(limit, recheck_after) => {
+            links_to_check(connect(&pool)?, limit, recheck_after)
+        }
+        Queries::StoreHealth(short_code, result) => {
+            store_health(connect(&pool)?, &short_code, &result)
+        }
+        Queries::ListBrokenLinks(api_key, cursor, limit) => {
+            list_broken_links(connect(&pool)?, &api_key, cursor.as_deref(), limit)
+        }
+        Queries::GetUserId(api_key) => get_user_id(connect(&pool)?, &api_key),
+        Queries::CheckAdmin(api_key) => check_admin(connect(&pool)?, &api_key),
+        Queries::FindNamed(domain, names) => find_named(connect(&pool)?, domain.as_deref(), &names),
+        Queries::AllLinks => all_links(connect(&pool)?),
+        Queries::SetBlocked(links) => set_blocked(connect(&pool)?, &links),
+        Queries::DatabaseSize => database_size(connect(&pool)?),
+        Queries::CheckWritable => check_writable(connect(&pool)?),
+    })
+    .map_err(|err| match err {
+        BlockingError::Error(err) => classify(err),
+        BlockingError::Canceled => Error::from(DBError::Canceled),
     })
-    .map_err(AWError::from)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// Returns a pool of one connection to a new in-memory database with
+    /// all migrations applied, and the API key of its first user, an admin
+    pub fn test_db() -> (Pool, String) {
+        let pool = Pool::builder()
+            .max_size(1)
+            .build(SqliteConnectionManager::memory())
+            .unwrap();
+        init_database(pool.get().unwrap()).unwrap();
+        let key = user(&pool, true);
+        (pool, key)
+    }
+
+    /// Creates a user and returns its API key
+    pub fn user(pool: &Pool, is_admin: bool) -> String {
+        match create_user(pool.get().unwrap(), 0, is_admin).unwrap() {
+            DBValue::String(key) => key,
+            value => panic!("Unexpected value {:?}", value),
+        }
+    }
+
+    fn link(url: &str) -> LinkPostData {
+        LinkPostData {
+            url: url.to_owned(),
+            title: None,
+            description: None,
+            redirect: None,
+            image: None,
+            unfurl: None,
+            passthrough: None,
+            name: None,
+            domain: None,
+        }
+    }
+
+    fn store(pool: &Pool, key: &str, data: LinkPostData) -> Result<String> {
+        let data = UrlPostData {
+            link: data,
+            key: key.to_owned(),
+        };
+        match store_url(pool.get()?, &data, false)? {
+            DBValue::Stored(stored) => Ok(stored[0].0.clone()),
+            value => panic!("Unexpected value {:?}", value),
+        }
+    }
+
+    fn visits(pool: &Pool, short_code: &str) -> i64 {
+        find_link(&pool.get().unwrap(), short_code).unwrap().visits
+    }
+
+    fn named(url: &str, name: &str) -> LinkPostData {
+        LinkPostData {
+            name: Some(name.to_owned()),
+            ..link(url)
+        }
+    }
+
+    fn is_conflict(result: Result<String>) -> bool {
+        matches!(
+            result.map_err(|err| err.downcast::<DBError>()),
+            Err(Ok(DBError::Conflict))
+        )
+    }
+
+    #[test]
+    fn names_cannot_take_over_short_codes() {
+        let (pool, key) = test_db();
+        for _ in 0..11 {
+            store(&pool, &key, link("https://example.com")).unwrap();
+        }
+
+        // the 11th link has the short code b
+        assert!(is_conflict(store(&pool, &key, named("https://example.org", "b"))));
+        assert!(is_conflict(store(&pool, &key, named("https://example.org", "b/x"))));
+        assert!(is_conflict(store(&pool, &key, named("https://example.org", "a"))));
+        assert!(store(&pool, &key, named("https://example.org", "docs")).is_ok());
+    }
+
+    #[test]
+    fn short_codes_skip_names() {
+        let (pool, key) = test_db();
+        assert_eq!(store(&pool, &key, link("https://example.com")).unwrap(), "1");
+        store(&pool, &key, named("https://example.org", "3/x")).unwrap();
+
+        // 3 is the start of a name, so the next link gets 4
+        assert_eq!(store(&pool, &key, link("https://example.com")).unwrap(), "4");
+        assert!(find_link(&pool.get().unwrap(), "3").is_err());
+
+        // not even its own name may start with the short code of a link
+        assert_eq!(store(&pool, &key, named("https://example.org", "5")).unwrap(), "6");
+        assert!(find_link(&pool.get().unwrap(), "5").is_err());
+    }
+
+    #[test]
+    fn bulk_name_conflicts_fail_on_their_own() {
+        let (pool, key) = test_db();
+        store(&pool, &key, named("https://example.org", "docs")).unwrap();
+
+        let links = [
+            link("https://example.com/1"),
+            named("https://example.com/2", "docs"),
+            named("https://example.com/3", "wiki"),
+            named("https://example.com/4", "wiki"),
+            named("https://example.com/5", "1"),
+            link("https://example.com/6"),
+        ];
+        let stored = match store_urls(pool.get().unwrap(), &key, &links, false).unwrap() {
+            DBValue::StoredEach(stored) => stored,
+            value => panic!("Unexpected value {:?}", value),
+        };
+        let codes = stored
+            .iter()
+            .map(|stored| stored.as_ref().map(|(code, _)| code.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(codes, vec![Some("2"), None, Some("3"), None, None, Some("4")]);
+        assert_eq!(find_link(&pool.get().unwrap(), "3").unwrap().url, "https://example.com/3");
+        assert_eq!(find_link(&pool.get().unwrap(), "4").unwrap().url, "https://example.com/6");
+    }
+
+    #[test]
+    fn new_targets_unblock_links() {
+        let (pool, key) = test_db();
+        let code = store(&pool, &key, link("https://example.com")).unwrap();
+        set_blocked(pool.get().unwrap(), &[(code.clone(), true)]).unwrap();
+
+        let patch = |url: Option<&str>| UrlPatchData {
+            url: url.map(String::from),
+            title: Some(String::from("Title")),
+            description: None,
+            redirect: None,
+            image: None,
+            unfurl: None,
+            passthrough: None,
+        };
+        update_link(pool.get().unwrap(), &key, &code, &patch(None)).unwrap();
+        assert!(find_link(&pool.get().unwrap(), &code).unwrap().blocked_at.is_some());
+
+        update_link(pool.get().unwrap(), &key, &code, &patch(Some("https://example.org"))).unwrap();
+        let link = find_link(&pool.get().unwrap(), &code).unwrap();
+        assert_eq!(link.url, "https://example.org");
+        assert!(link.blocked_at.is_none());
+    }
+
+    #[test]
+    fn counts_visits() {
+        let (pool, key) = test_db();
+        let code = store(&pool, &key, link("https://example.com")).unwrap();
+
+        get_url(pool.get().unwrap(), &code).unwrap();
+        get_url(pool.get().unwrap(), &code).unwrap();
+        assert_eq!(visits(&pool, &code), 2);
+    }
+
+    #[test]
+    fn blocked_links_do_not_count_visits() {
+        let (pool, key) = test_db();
+        let code = store(&pool, &key, link("https://example.com")).unwrap();
+        set_blocked(pool.get().unwrap(), &[(code.clone(), true)]).unwrap();
+
+        match get_url(pool.get().unwrap(), &code).unwrap() {
+            DBValue::Link(link) => assert!(link.blocked_at.is_some()),
+            value => panic!("Unexpected value {:?}", value),
+        }
+        assert_eq!(visits(&pool, &code), 0);
+    }
+
+    /// Returns a database like the ones of the first release, before any
+    /// migrations, with version migrations applied on top
+    fn old_db(version: usize) -> Pool {
+        let pool = Pool::builder()
+            .max_size(1)
+            .build(SqliteConnectionManager::memory())
+            .unwrap();
+        let conn = pool.get().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE Users(
+               rowid INTEGER NOT NULL, api_key TEXT UNIQUE NOT NULL,
+               rate_limit INTEGER DEFAULT 0, is_admin SMALLINT DEFAULT 0,
+               PRIMARY KEY(rowid));
+             CREATE TABLE URLs(
+               url TEXT NOT NULL, visits INTEGER DEFAULT 0, title TEXT,
+               description TEXT, created_at DATETIME, user_id INTEGER NOT NULL,
+               FOREIGN KEY(user_id) REFERENCES Users(rowid));
+             INSERT INTO Users(api_key, is_admin) VALUES ('key', 1);
+             INSERT INTO URLs(url, visits, title, created_at, user_id)
+               VALUES ('https://example.com', 3, 'Example', '2021-05-01 12:00:00', 1);",
+        )
+        .unwrap();
+        for migration in &MIGRATIONS[..version] {
+            conn.execute_batch(migration).unwrap();
+        }
+        conn.pragma_update(None, "user_version", &(version as i64))
+            .unwrap();
+        drop(conn);
+        pool
+    }
+
+    fn version(conn: &Connection) -> i64 {
+        conn.query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0))
+            .unwrap()
+    }
+
+    fn is_invalid_schema(result: Result) -> bool {
+        matches!(
+            result.map_err(|err| err.downcast::<DBError>()),
+            Err(Ok(DBError::InvalidSchema))
+        )
+    }
+
+    #[test]
+    fn checks_the_schema() {
+        let (pool, _) = test_db();
+        assert!(check_database_schema(pool.get().unwrap()).is_ok());
+        // initializing again changes nothing
+        assert!(init_database(pool.get().unwrap()).is_ok());
+        assert!(check_database_schema(pool.get().unwrap()).is_ok());
+
+        // databases of newer versions are not used
+        let conn = pool.get().unwrap();
+        conn.pragma_update(None, "user_version", &(MIGRATIONS.len() as i64 + 1))
+            .unwrap();
+        assert!(is_invalid_schema(check_database_schema(conn)));
+
+        let pool = Pool::builder()
+            .max_size(1)
+            .build(SqliteConnectionManager::memory())
+            .unwrap();
+        assert!(is_invalid_schema(check_database_schema(pool.get().unwrap())));
+    }
+
+    #[test]
+    fn migrates_old_databases() {
+        for n in 0..MIGRATIONS.len() {
+            let pool = old_db(n);
+            assert!(is_invalid_schema(check_database_schema(pool.get().unwrap())));
+
+            init_database(pool.get().unwrap()).unwrap();
+            assert!(check_database_schema(pool.get().unwrap()).is_ok(), "{}", n);
+
+            let link = find_link(&pool.get().unwrap(), "1").unwrap();
+            assert_eq!(link.url, "https://example.com");
+            assert_eq!(link.title, "Example");
+            assert_eq!(link.created_at, "2021-05-01T12:00:00Z");
+            assert_eq!(link.visits, 3);
+            assert!(!link.passthrough);
+            assert!(link.health.is_none() && link.blocked_at.is_none());
+        }
+    }
+
+    #[test]
+    fn failed_migrations_can_be_retried() {
+        // the index of the last migration cannot be created, after the
+        // column it adds
+        let pool = old_db(MIGRATIONS.len() - 1);
+        pool.get()
+            .unwrap()
+            .execute_batch("CREATE TABLE idx_domain_name(x);")
+            .unwrap();
+
+        assert!(init_database(pool.get().unwrap()).is_err());
+        let conn = pool.get().unwrap();
+        assert_eq!(version(&conn), MIGRATIONS.len() as i64 - 1);
+        assert!(conn.is_autocommit());
+
+        conn.execute_batch("DROP TABLE idx_domain_name;").unwrap();
+        init_database(conn).unwrap();
+        assert!(check_database_schema(pool.get().unwrap()).is_ok());
+    }
 }
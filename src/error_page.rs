@@ -0,0 +1,99 @@
+//! Error pages for browsers and problem details for API clients.
+//!
+//! Handlers respond to errors with JSON (see response_types::Error), which is
+//! replaced by an HTML page for requests that prefer HTML according to their
+//! `Accept` header, like browsers opening a dead short link. The API never
+//! responds with HTML. Clients that accept `application/problem+json` get
+//! the error as RFC 7807 problem details instead.
+
+use super::response_types::Error;
+use super::{render, templates};
+use actix_web::{
+    dev::ServiceResponse,
+    http::header::{HeaderMap, ACCEPT, CONTENT_LENGTH, CONTENT_TYPE},
+    HttpResponse,
+};
+
+/// Returns the quality of mime in the Accept header value accept, if listed
+fn quality(accept: &str, mime: &str) -> Option<f32> {
+    accept
+        .split(',')
+        .filter_map(|item| {
+            let mut params = item.split(';');
+            if !params.next()?.trim().eq_ignore_ascii_case(mime) {
+                return None;
+            }
+            let quality = params.find_map(|param| param.trim().strip_prefix("q="));
+            Some(quality.and_then(|q| q.parse().ok()).unwrap_or(1.0))
+        })
+        .fold(None, |max: Option<f32>, q| {
+            Some(max.map_or(q, |max| max.max(q)))
+        })
+}
+
+/// The representations of an error response
+#[derive(PartialEq)]
+enum Format {
+    Json,
+    Problem,
+    Html,
+}
+
+/// Picks the representation a request with headers prefers: RFC 7807 problem
+/// details if asked for at least as much as plain JSON, HTML if preferred
+/// over JSON and JSON otherwise, also for requests without Accept header
+fn preferred_format(headers: &HeaderMap) -> Format {
+    let accept = match headers.get(ACCEPT).and_then(|value| value.to_str().ok()) {
+        Some(accept) => accept,
+        None => return Format::Json,
+    };
+    let json = quality(accept, "application/json");
+    match quality(accept, "application/problem+json") {
+        Some(problem) if problem > 0.0 && json.is_none_or(|json| problem >= json) => {
+            return Format::Problem
+        }
+        _ => {}
+    }
+    match (quality(accept, "text/html"), json) {
+        (Some(html), Some(json)) if html > 0.0 && html >= json => Format::Html,
+        (Some(html), None) if html > 0.0 => Format::Html,
+        _ => Format::Json,
+    }
+}
+
+/// Replaces the JSON body of an error response with problem details or the
+/// error page, if the request prefers them. API requests never get the page.
+pub fn negotiate(response: ServiceResponse) -> ServiceResponse {
+    let req = response.request();
+    let format = match preferred_format(req.headers()) {
+        Format::Html if req.path().starts_with("/api/") => Format::Json,
+        format => format,
+    };
+    if format == Format::Json {
+        return response;
+    }
+    let err = match response
+        .response()
+        .error()
+        .and_then(|err| err.as_error::<Error>())
+    {
+        Some(err) => err,
+        None => return response,
+    };
+
+    let mut replaced = HttpResponse::build(response.status());
+    for (name, value) in response.headers() {
+        if name != CONTENT_TYPE && name != CONTENT_LENGTH {
+            replaced.header(name.clone(), value.clone());
+        }
+    }
+    let replaced = match format {
+        Format::Problem => replaced
+            .content_type("application/problem+json")
+            .body(err.to_problem().to_string()),
+        _ => replaced
+            .content_type("text/html; charset=utf-8")
+            .body(render!(templates::error_html, err.status, err.msg)),
+    };
+    response.into_response(replaced)
+}
@@ -0,0 +1,443 @@
+use super::config::FetchConfig;
+use super::metrics;
+use actix_connect::{Connect, ConnectError, Connection, TcpConnector};
+use actix_service::Service;
+use actix_web::{
+    error::BlockingError,
+    http::header::{CONTENT_TYPE, LOCATION, USER_AGENT},
+    http::{HeaderMap, Method, Uri},
+    rt::net::TcpStream,
+};
+use awc::error::{ConnectError as ClientConnectError, SendRequestError};
+use futures::future::LocalBoxFuture;
+use futures::StreamExt;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use url::Url;
+
+/// Reasons why fetching a URL failed
+#[derive(Debug)]
+pub enum FetchError {
+    /// The host resolves to a private or local address
+    Blocked,
+    /// Only http and https can be fetched
+    UnsupportedScheme,
+    TooManyRedirects,
+    InvalidRedirect,
+    Request(String),
+}
+
+impl Display for FetchError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            FetchError::Blocked => write!(f, "Host resolves to a private address"),
+            FetchError::UnsupportedScheme => write!(f, "Unsupported URL scheme"),
+            FetchError::TooManyRedirects => write!(f, "Too many redirects"),
+            FetchError::InvalidRedirect => write!(f, "Invalid redirect location"),
+            FetchError::Request(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+/// Wraps errors of the HTTP client, keeping only the first line
+/// because some include a backtrace
+fn request_error(err: impl Display) -> FetchError {
+    let msg = err.to_string();
+    FetchError::Request(msg.lines().next().unwrap_or_default().to_owned())
+}
+
+/// Like request_error, but recognizes hosts refused by the GuardedConnector
+fn send_error(err: SendRequestError) -> FetchError {
+    match &err {
+        SendRequestError::Connect(ClientConnectError::Io(io_err))
+            if io_err.kind() == io::ErrorKind::PermissionDenied =>
+        {
+            FetchError::Blocked
+        }
+        _ => request_error(err),
+    }
+}
+
+/// A fetched resource, after following all redirects
+pub struct Fetched {
+    pub status: u16,
+    /// the final URL
+    pub url: Url,
+    /// at most max_body bytes of the body, empty if not requested
+    pub body: Vec<u8>,
+}
+
+/// Checks if ip is reachable from the public internet
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_unspecified()
+                || a == 0
+                || (a == 100 && (64..128).contains(&b)))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || (first & 0xfe00) == 0xfc00
+                    || (first & 0xffc0) == 0xfe80)
+            }
+        },
+    }
+}
+
+/// Fails if there are no addresses or any of them is not public
+fn ensure_public(addresses: &[SocketAddr]) -> Result<(), FetchError> {
+    if !addresses.is_empty() && addresses.iter().all(|addr| is_public(addr.ip())) {
+        Ok(())
+    } else {
+        Err(FetchError::Blocked)
+    }
+}
+
+/// Resolves the host of a request with the system resolver
+async fn resolve(host: &str, port: u16) -> Result<Vec<SocketAddr>, ConnectError> {
+    // IPv6 hosts of URIs are enclosed in brackets
+    let host = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_owned();
+
+    metrics::block(move || {
+        (host.as_str(), port)
+            .to_socket_addrs()
+            .map(|addrs| addrs.collect::<Vec<SocketAddr>>())
+    })
+    .await
+    .map_err(|err| match err {
+        BlockingError::Error(err) => ConnectError::Io(err),
+        BlockingError::Canceled => ConnectError::Unresolved,
+    })
+}
+
+/// TCP connector of the Fetcher that resolves hosts itself and, unless
+/// private addresses are allowed, refuses hosts with any private address.
+/// It connects to exactly the addresses it checked, so that a second DNS
+/// answer cannot point the connection somewhere else (DNS rebinding).
+#[derive(Clone)]
+struct GuardedConnector {
+    allow_private_addresses: bool,
+}
+
+impl Service for GuardedConnector {
+    type Request = Connect<Uri>;
+    type Response = Connection<Uri, TcpStream>;
+    type Error = ConnectError;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Connect<Uri>) -> Self::Future {
+        let allow_private_addresses = self.allow_private_addresses;
+
+        Box::pin(async move {
+            let addresses = resolve(req.host(), req.port()).await?;
+            if addresses.is_empty() {
+                return Err(ConnectError::NoRecords);
+            }
+            if !allow_private_addresses {
+                ensure_public(&addresses).map_err(|err| {
+                    ConnectError::Io(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        err.to_string(),
+                    ))
+                })?;
+            }
+            TcpConnector::new().call(req.set_addrs(addresses)).await
+        })
+    }
+}
+
+/// Reads the location header of a redirect, relative to url
+fn redirect_location(url: &Url, headers: &HeaderMap) -> Result<Url, FetchError> {
+    let location = headers
+        .get(LOCATION)
+        .and_then(|location| location.to_str().ok())
+        .ok_or(FetchError::InvalidRedirect)?;
+    url.join(location).map_err(|_| FetchError::InvalidRedirect)
+}
+
+/// A HTTP client that follows redirects and refuses to connect to
+/// private addresses, unless configured otherwise.
+/// Used for outgoing requests to user submitted URLs.
+pub struct Fetcher {
+    client: awc::Client,
+    config: FetchConfig,
+}
+
+impl Fetcher {
+    pub fn new(config: &FetchConfig) -> Fetcher {
+        let timeout = Duration::from_secs(config.timeout);
+        let connector = awc::Connector::new()
+            .connector(GuardedConnector {
+                allow_private_addresses: config.allow_private_addresses,
+            })
+            .timeout(timeout)
+            .finish();
+        let client = awc::Client::builder()
+            .connector(connector)
+            .timeout(timeout)
+            .disable_redirects()
+            .header(USER_AGENT, config.user_agent.as_str())
+            .finish();
+
+        Fetcher {
+            client,
+            config: config.clone(),
+        }
+    }
+
+    /// Makes sure that url can be requested, the addresses of its host
+    /// are checked when connecting, see GuardedConnector
+    fn check(&self, url: &Url) -> Result<(), FetchError> {
+        if url.scheme() != "http" && url.scheme() != "https" {
+            return Err(FetchError::UnsupportedScheme);
+        }
+        if url.host().is_none() {
+            return Err(FetchError::Blocked);
+        }
+        Ok(())
+    }
+
+    /// Requests url with HEAD without following redirects and returns
+    /// the redirect location, if the response is a redirect
+    pub async fn location(&self, url: &Url) -> Result<Option<Url>, FetchError> {
+        self.check(url)?;
+
+        let response = self
+            .client
+            .head(url.as_str())
+            .send()
+            .await
+            .map_err(send_error)?;
+
+        if response.status().is_redirection() {
+            redirect_location(url, response.headers()).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Requests url with method, following redirects. If read_body returns
+    /// true for the content type of the final response, up to max_body bytes
+    /// of its body are read.
+    pub async fn fetch<F>(
+        &self,
+        method: Method,
+        url: Url,
+        max_body: usize,
+        read_body: F,
+    ) -> Result<Fetched, FetchError>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let mut url = url;
+
+        for _ in 0..=self.config.max_redirects {
+            self.check(&url)?;
+
+            let mut response = self
+                .client
+                .request(method.clone(), url.as_str())
+                .send()
+                .await
+                .map_err(send_error)?;
+
+            if response.status().is_redirection() {
+                url = redirect_location(&url, response.headers())?;
+                continue;
+            }
+
+            let content_type = response
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or("");
+
+            let mut body = Vec::new();
+            if max_body > 0 && read_body(content_type) {
+                while let Some(chunk) = response.next().await {
+                    let chunk = chunk.map_err(request_error)?;
+                    let remaining = max_body - body.len();
+                    body.extend_from_slice(&chunk[..chunk.len().min(remaining)]);
+                    if body.len() >= max_body {
+                        break;
+                    }
+                }
+            }
+
+            return Ok(Fetched {
+                status: response.status().as_u16(),
+                url,
+                body,
+            });
+        }
+
+        Err(FetchError::TooManyRedirects)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use actix_web::{test, web, App, HttpResponse};
+
+    /// Starts a local server with the pages used by the tests, /page and
+    /// /redirect also answer HEAD requests like health checks send
+    pub fn fixture_server() -> test::TestServer {
+        test::start(|| {
+            App::new()
+                .route(
+                    "/page",
+                    web::route().to(|| {
+                        HttpResponse::Ok().content_type("text/html").body(
+                            "<html><head><title>Fixture &amp; page</title>\
+                             <meta name=\"description\" content=\"Served by the tests\">\
+                             <link rel=\"canonical\" href=\"/canonical\"></head></html>",
+                        )
+                    }),
+                )
+                .route(
+                    "/redirect",
+                    web::route().to(|| HttpResponse::Found().header(LOCATION, "/page").finish()),
+                )
+                .route(
+                    "/large",
+                    web::get().to(|| {
+                        let padding = "<!-- padding -->".repeat(1024);
+                        HttpResponse::Ok()
+                            .content_type("text/html")
+                            .body(format!("<html>{}<title>Too late</title></html>", padding))
+                    }),
+                )
+                .route(
+                    "/slow",
+                    web::get().to(|| async {
+                        actix_web::rt::time::delay_for(Duration::from_secs(3)).await;
+                        let response = HttpResponse::Ok()
+                            .content_type("text/html")
+                            .body("<title>Slow</title>");
+                        Ok::<_, actix_web::Error>(response)
+                    }),
+                )
+                .route(
+                    "/data.json",
+                    web::get().to(|| {
+                        HttpResponse::Ok()
+                            .content_type("application/json")
+                            .body("{\"title\": \"JSON\"}")
+                    }),
+                )
+        })
+    }
+
+    /// Fetcher for the fixture server, which has a private address
+    pub fn fetcher(allow_private_addresses: bool) -> Fetcher {
+        Fetcher::new(&FetchConfig {
+            timeout: 1,
+            allow_private_addresses,
+            ..FetchConfig::default()
+        })
+    }
+
+    fn url(server: &test::TestServer, path: &str) -> Url {
+        Url::parse(&server.url(path)).unwrap()
+    }
+
+    #[test]
+    fn detects_private_addresses() {
+        for ip in &[
+            "127.0.0.1",
+            "10.1.2.3",
+            "192.168.0.1",
+            "100.64.0.1",
+            "::1",
+            "fd00::1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in &["1.1.1.1", "2606:4700:4700::1111", "::ffff:1.1.1.1"] {
+            assert!(is_public(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn follows_redirects_and_limits_the_body() {
+        let server = fixture_server();
+        let fetcher = fetcher(true);
+
+        let fetched = fetcher
+            .fetch(Method::GET, url(&server, "/redirect"), 1024, |_| true)
+            .await
+            .unwrap();
+        assert_eq!(fetched.status, 200);
+        assert_eq!(fetched.url.path(), "/page");
+
+        let fetched = fetcher
+            .fetch(Method::GET, url(&server, "/large"), 1024, |_| true)
+            .await
+            .unwrap();
+        assert_eq!(fetched.body.len(), 1024);
+
+        let fetched = fetcher
+            .fetch(
+                Method::GET,
+                url(&server, "/data.json"),
+                1024,
+                |content_type| content_type == "text/html",
+            )
+            .await
+            .unwrap();
+        assert!(fetched.body.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn times_out() {
+        let server = fixture_server();
+        let result = fetcher(true)
+            .fetch(Method::GET, url(&server, "/slow"), 1024, |_| true)
+            .await;
+        assert!(matches!(result, Err(FetchError::Request(_))));
+    }
+
+    #[actix_rt::test]
+    async fn blocks_private_addresses() {
+        let server = fixture_server();
+        let fetcher = fetcher(false);
+
+        let result = fetcher
+            .fetch(Method::GET, url(&server, "/page"), 1024, |_| true)
+            .await;
+        assert!(matches!(result, Err(FetchError::Blocked)));
+
+        // hostnames are resolved when connecting
+        let mut by_name = url(&server, "/page");
+        by_name.set_host(Some("localhost")).unwrap();
+        let result = fetcher
+            .fetch(Method::GET, by_name.clone(), 1024, |_| true)
+            .await;
+        assert!(matches!(result, Err(FetchError::Blocked)));
+        assert!(matches!(
+            fetcher.location(&by_name).await,
+            Err(FetchError::Blocked)
+        ));
+    }
+}
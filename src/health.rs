@@ -0,0 +1,207 @@
+//! Optional background job that periodically checks the targets of all links
+//! and records their status code, final URL and the time of the check.
+//!
+//! Links are checked one after another with a configurable delay between
+//! requests, starting with links that have never been checked. Targets that
+//! failed too often in a row can be replaced by a fallback URL when
+//! redirecting, like a link to a web archive.
+//!
+//! Targets on private addresses are not checked unless
+//! `fetch.allow_private_addresses` is set, so intranet links never count as
+//! broken.
+
+use super::config::{Config, FetchConfig, HealthConfig};
+use super::db::{self, DBValue, Link, Pool, Queries};
+use super::fetch::{FetchError, Fetcher};
+use actix_web::{http::Method, rt::time::delay_for};
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::time::Duration;
+use url::Url;
+
+/// Number of links fetched from the database at once
+const BATCH_SIZE: u32 = 100;
+
+/// Time to wait before looking for due links again, if there were none
+const IDLE: Duration = Duration::from_secs(60);
+
+/// The result of the last check of a link target
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct Health {
+    /// status code of the final response, none if the request failed
+    pub status: Option<u16>,
+    /// the URL after following all redirects
+    pub final_url: Option<String>,
+    pub error: Option<String>,
+    /// in UTC, like 2021-05-01T12:00:00Z
+    pub checked_at: String,
+    /// failed checks in a row
+    pub failures: i64,
+}
+
+/// The outcome of a single check, as stored with Queries::StoreHealth
+#[derive(Debug)]
+pub struct CheckResult {
+    pub status: Option<u16>,
+    pub final_url: Option<String>,
+    pub error: Option<String>,
+}
+
+impl CheckResult {
+    /// Failed requests and error responses count as broken, except for
+    /// responses that usually mean that the target exists but refuses bots
+    /// and targets that were not checked
+    pub fn is_broken(&self) -> bool {
+        match self.status {
+            Some(401) | Some(403) | Some(429) => false,
+            Some(status) => status >= 400,
+            None => self.error.is_some(),
+        }
+    }
+}
+
+/// Starts the checker if enabled in config.
+/// Must be called from within the actix runtime.
+pub fn start(pool: Pool, config: &HealthConfig, fetch_config: &FetchConfig) {
+    if !config.enabled {
+        return;
+    }
+
+    let config = config.clone();
+    let fetcher = Fetcher::new(fetch_config);
+
+    actix_web::rt::spawn(async move {
+        loop {
+            let query = Queries::LinksToCheck(BATCH_SIZE, config.recheck_after);
+            let links = match db::query(&pool, query).await {
+                Ok(DBValue::Links(links, _)) => links,
+                Ok(value) => {
+                    debug!(
+                        "Got unexpected type back from LinksToCheck query: {:#?}",
+                        value
+                    );
+                    Vec::new()
+                }
+                Err(err) => {
+                    error!("Could not load links to check: {}", err);
+                    Vec::new()
+                }
+            };
+
+            if links.is_empty() {
+                delay_for(IDLE).await;
+                continue;
+            }
+
+            for link in links {
+                let result = check(&fetcher, &link.url).await;
+                debug!("Checked {}: {:?}", link.url, result);
+
+                let query = Queries::StoreHealth(link.code, result);
+                if let Err(err) = db::query(&pool, query).await {
+                    error!("Could not store health of {}: {}", link.url, err);
+                }
+                delay_for(Duration::from_millis(config.delay)).await;
+            }
+        }
+    });
+}
+
+/// Requests url with HEAD, or GET for servers that don't support HEAD.
+/// Targets that are no web pages, like app links, and targets that the
+/// fetcher may not request, like intranet links, are not checked.
+async fn check(fetcher: &Fetcher, url: &str) -> CheckResult {
+    let not_checked = CheckResult {
+        status: None,
+        final_url: None,
+        error: None,
+    };
+    let url = match Url::parse(url) {
+        Ok(url) if url.scheme() != "http" && url.scheme() != "https" => return not_checked,
+        Ok(url) => url,
+        Err(err) => {
+            return CheckResult {
+                status: None,
+                final_url: None,
+                error: Some(err.to_string()),
+            }
+        }
+    };
+
+    let mut fetched = fetcher.fetch(Method::HEAD, url.clone(), 0, |_| false).await;
+    if let Ok(405) | Ok(501) = fetched.as_ref().map(|fetched| fetched.status) {
+        fetched = fetcher.fetch(Method::GET, url, 0, |_| false).await;
+    }
+
+    match fetched {
+        Ok(fetched) => CheckResult {
+            status: Some(fetched.status),
+            final_url: Some(fetched.url.to_string()),
+            error: None,
+        },
+        Err(FetchError::Blocked) => not_checked,
+        Err(err) => CheckResult {
+            status: None,
+            final_url: None,
+            error: Some(err.to_string()),
+        },
+    }
+}
+
+/// Returns the configured fallback URL for links with a dead target
+pub fn fallback_url(link: &Link, config: &Config) -> Option<String> {
+    let health = link.health.as_ref()?;
+    if health.failures < config.health.fallback_after.max(1) {
+        return None;
+    }
+
+    config
+        .health
+        .fallback
+        .as_ref()
+        .map(|fallback| fallback.replace("{url}", &link.url))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fetch::tests::{fetcher, fixture_server};
+
+    #[test]
+    fn counts_errors_as_broken() {
+        let result = |status, error: Option<&str>| CheckResult {
+            status,
+            final_url: None,
+            error: error.map(String::from),
+        };
+        assert!(!result(Some(200), None).is_broken());
+        assert!(!result(Some(403), None).is_broken());
+        assert!(!result(None, None).is_broken());
+        assert!(result(Some(404), None).is_broken());
+        assert!(result(Some(500), None).is_broken());
+        assert!(result(None, Some("Request failed")).is_broken());
+    }
+
+    #[actix_rt::test]
+    async fn checks_targets() {
+        let server = fixture_server();
+        let fetcher = fetcher(true);
+
+        let result = check(&fetcher, &server.url("/redirect")).await;
+        assert_eq!(result.status, Some(200));
+        assert!(result.final_url.unwrap().ends_with("/page"));
+        assert!(check(&fetcher, &server.url("/missing")).await.is_broken());
+
+        let result = check(&fetcher, "mailto:k0r@example.com").await;
+        assert!(result.status.is_none() && result.error.is_none());
+        assert!(check(&fetcher, "not a url").await.is_broken());
+    }
+
+    #[actix_rt::test]
+    async fn skips_private_addresses() {
+        let server = fixture_server();
+        let result = check(&fetcher(false), &server.url("/missing")).await;
+        assert!(result.status.is_none() && result.error.is_none());
+        assert!(!result.is_broken());
+    }
+}
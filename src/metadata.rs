@@ -0,0 +1,367 @@
+//! Optional background job that fetches the targets of new links and fills
+//! in their missing title and description, and their canonical URL.
+//!
+//! New links are queued and processed one after another by a single worker,
+//! which keeps the load on the fetched sites and on k0r itself low, even
+//! when hundreds of links are created with one bulk request. The queue is
+//! bounded, links that don't fit are skipped.
+
+use super::config::{FetchConfig, MetadataConfig};
+use super::db::{self, LinkPostData, Pool, Queries};
+use super::fetch::Fetcher;
+use super::template;
+use actix_web::http::Method;
+use futures::channel::mpsc::{channel, Sender};
+use futures::StreamExt;
+use std::collections::HashMap;
+use url::Url;
+
+/// Metadata extracted from a HTML page
+#[derive(Debug, Default)]
+pub struct Metadata {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub canonical_url: Option<String>,
+}
+
+/// Queue of links to fetch metadata for, as (short_code, url)
+#[derive(Clone)]
+pub struct Queue(Option<Sender<(String, String)>>);
+
+impl Queue {
+    /// Starts the worker if enabled in config.
+    /// Must be called from within the actix runtime.
+    pub fn start(pool: Pool, config: &MetadataConfig, fetch_config: &FetchConfig) -> Queue {
+        if !config.enabled {
+            return Queue(None);
+        }
+
+        let (sender, mut receiver) = channel::<(String, String)>(config.queue_size);
+        let config = config.clone();
+        let fetcher = Fetcher::new(fetch_config);
+
+        actix_web::rt::spawn(async move {
+            while let Some((short_code, url)) = receiver.next().await {
+                let metadata = match fetch(&fetcher, &config, &url).await {
+                    Ok(metadata) => metadata,
+                    Err(err) => {
+                        debug!("Could not fetch metadata of {}: {}", url, err);
+                        continue;
+                    }
+                };
+
+                debug!("Fetched metadata of {}: {:?}", url, metadata);
+                let query = Queries::StoreMetadata(short_code, metadata);
+                if let Err(err) = db::query(&pool, query).await {
+                    error!("Could not store metadata of {}: {}", url, err);
+                }
+            }
+        });
+
+        Queue(Some(sender))
+    }
+
+    /// Queues a new link if its title or description is missing,
+    /// does nothing if metadata fetching is disabled
+    pub fn push(&self, short_code: &str, link: &LinkPostData) {
+        let is_missing = |value: &Option<String>| value.as_deref().unwrap_or("").is_empty();
+        // templates have no single target to fetch
+        let is_template = link.name.is_some() && template::is_template(&link.url);
+        if is_template || (!is_missing(&link.title) && !is_missing(&link.description)) {
+            return;
+        }
+        if let Some(sender) = &self.0 {
+            let mut sender = sender.clone();
+            if let Err(err) = sender.try_send((short_code.to_owned(), link.url.clone())) {
+                warn!("Skipping metadata of {}: {}", short_code, err);
+            }
+        }
+    }
+}
+
+/// Fetches url and parses the metadata, if its content type is allowed
+async fn fetch(fetcher: &Fetcher, config: &MetadataConfig, url: &str) -> Result<Metadata, String> {
+    let url = Url::parse(url).map_err(|err| err.to_string())?;
+    let is_allowed = |content_type: &str| {
+        let mime = content_type.split(';').next().unwrap_or("").trim();
+        config
+            .content_types
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(mime))
+    };
+
+    let fetched = fetcher
+        .fetch(Method::GET, url, config.max_size, is_allowed)
+        .await
+        .map_err(|err| err.to_string())?;
+
+    if fetched.status >= 400 {
+        return Err(format!("Got status {}", fetched.status));
+    }
+    if fetched.body.is_empty() {
+        return Err(String::from("Content type not allowed or empty body"));
+    }
+
+    Ok(parse(&String::from_utf8_lossy(&fetched.body), &fetched.url))
+}
+
+/// Decodes the most common HTML entities
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let end = match rest.find(';') {
+            Some(end) if end <= 10 => end,
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+                continue;
+            }
+        };
+
+        let entity = &rest[1..end];
+        let c = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            _ if entity.starts_with("#x") || entity.starts_with("#X") => {
+                u32::from_str_radix(&entity[2..], 16)
+                    .ok()
+                    .and_then(char::from_u32)
+            }
+            _ if entity.starts_with('#') => entity[1..].parse().ok().and_then(char::from_u32),
+            _ => None,
+        };
+
+        match c {
+            Some(c) => {
+                decoded.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    decoded.push_str(rest);
+    decoded
+}
+
+/// Decodes entities, collapses whitespace and drops empty values
+fn clean(text: &str) -> Option<String> {
+    let text = decode_entities(text);
+    let text = text.split_whitespace().collect::<Vec<&str>>().join(" ");
+    Some(text).filter(|text| !text.is_empty())
+}
+
+/// Parses the attributes of a tag, like `name="a" content='b' c=d`
+fn attributes(tag: &str) -> HashMap<String, String> {
+    let mut attributes = HashMap::new();
+    let mut rest = tag.trim_start();
+
+    while !rest.is_empty() {
+        let name_end = rest
+            .find(|c: char| c == '=' || c.is_whitespace() || c == '/')
+            .unwrap_or(rest.len());
+        let name = rest[..name_end].to_ascii_lowercase();
+        rest = rest[name_end..].trim_start();
+
+        let mut value = String::new();
+        if let Some(after_eq) = rest.strip_prefix('=') {
+            rest = after_eq.trim_start();
+            let quote = rest.chars().next().filter(|c| *c == '"' || *c == '\'');
+            let (raw, remaining) = match quote {
+                Some(quote) => {
+                    let inner = &rest[1..];
+                    let end = inner.find(quote).unwrap_or(inner.len());
+                    (&inner[..end], inner.get(end + 1..).unwrap_or(""))
+                }
+                None => {
+                    let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+                    (&rest[..end], &rest[end..])
+                }
+            };
+            value = decode_entities(raw);
+            rest = remaining;
+        } else if name.is_empty() {
+            // skip stray characters like the slash of self-closing tags
+            rest = rest.get(1..).unwrap_or("");
+        }
+
+        if !name.is_empty() {
+            attributes.entry(name).or_insert(value);
+        }
+        rest = rest.trim_start();
+    }
+
+    attributes
+}
+
+/// Collects the attributes of all tags called name
+fn tags(html: &str, lowercase: &str, name: &str) -> Vec<HashMap<String, String>> {
+    let opening = format!("<{}", name);
+    let mut tags = Vec::new();
+    let mut offset = 0;
+
+    while let Some(start) = lowercase[offset..].find(&opening) {
+        let start = offset + start + opening.len();
+        let end = match lowercase[start..].find('>') {
+            Some(end) => start + end,
+            None => break,
+        };
+        // make sure that the tag name ends here, to not match <metadata>
+        if lowercase[start..].starts_with(|c: char| c.is_whitespace() || c == '/') {
+            tags.push(attributes(&html[start..end]));
+        }
+        offset = end;
+    }
+
+    tags
+}
+
+/// Extracts title, description and canonical URL from a HTML page,
+/// preferring OpenGraph data. Relative canonical URLs are resolved against url.
+pub fn parse(html: &str, url: &Url) -> Metadata {
+    // ASCII lowercase keeps byte offsets intact
+    let lowercase = html.to_ascii_lowercase();
+    let mut metadata = Metadata::default();
+    let mut og_url = None;
+
+    for meta in tags(html, &lowercase, "meta") {
+        let key = meta
+            .get("property")
+            .or_else(|| meta.get("name"))
+            .map(|key| key.to_ascii_lowercase());
+        let content = meta.get("content").and_then(|content| clean(content));
+
+        match (key.as_deref(), content) {
+            (Some("og:title"), Some(content)) => metadata.title = Some(content),
+            (Some("og:description"), Some(content)) => metadata.description = Some(content),
+            (Some("description"), Some(content)) if metadata.description.is_none() => {
+                metadata.description = Some(content)
+            }
+            (Some("og:url"), Some(content)) => og_url = Some(content),
+            _ => {}
+        }
+    }
+
+    if metadata.title.is_none() {
+        if let Some(start) = lowercase.find("<title") {
+            if let Some(start) = lowercase[start..].find('>').map(|end| start + end + 1) {
+                let end = lowercase[start..].find("</title>").map(|end| start + end);
+                metadata.title = end.and_then(|end| html.get(start..end)).and_then(clean);
+            }
+        }
+    }
+
+    let canonical = tags(html, &lowercase, "link")
+        .into_iter()
+        .find(|link| {
+            link.get("rel")
+                .map(|rel| rel.eq_ignore_ascii_case("canonical"))
+                .unwrap_or(false)
+        })
+        .and_then(|link| link.get("href").cloned())
+        .or(og_url);
+
+    metadata.canonical_url = canonical
+        .and_then(|canonical| url.join(canonical.trim()).ok())
+        .filter(|canonical| canonical.scheme() == "http" || canonical.scheme() == "https")
+        .map(|canonical| canonical.to_string());
+
+    metadata
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::fetch::tests::{fetcher, fixture_server};
+    use super::super::fetch::FetchError;
+    use super::*;
+
+    fn config(max_size: usize) -> MetadataConfig {
+        MetadataConfig {
+            enabled: true,
+            max_size,
+            ..MetadataConfig::default()
+        }
+    }
+
+    #[test]
+    fn parses_metadata() {
+        let url = Url::parse("https://example.com/a/b").unwrap();
+        let html = "<html><head><TITLE> A  &lt;title&gt; </TITLE>\
+                    <meta name=description content='Some &quot;text&quot;'>\
+                    <metadata content=\"ignored\">\
+                    <link rel=\"Canonical\" href=\"../c\"></head></html>";
+        let metadata = parse(html, &url);
+        assert_eq!(metadata.title.as_deref(), Some("A <title>"));
+        assert_eq!(metadata.description.as_deref(), Some("Some \"text\""));
+        assert_eq!(
+            metadata.canonical_url.as_deref(),
+            Some("https://example.com/c")
+        );
+    }
+
+    #[test]
+    fn prefers_opengraph() {
+        let url = Url::parse("https://example.com/").unwrap();
+        let html = "<title>Title</title><meta name=\"description\" content=\"Description\">\
+                    <meta property=\"og:title\" content=\"OG title\">\
+                    <meta property=\"og:description\" content=\"OG description\">\
+                    <meta property=\"og:url\" content=\"javascript:alert(1)\">";
+        let metadata = parse(html, &url);
+        assert_eq!(metadata.title.as_deref(), Some("OG title"));
+        assert_eq!(metadata.description.as_deref(), Some("OG description"));
+        assert_eq!(metadata.canonical_url, None);
+    }
+
+    #[actix_rt::test]
+    async fn fetches_metadata() {
+        let server = fixture_server();
+        let metadata = fetch(&fetcher(true), &config(1024), &server.url("/redirect"))
+            .await
+            .unwrap();
+        assert_eq!(metadata.title.as_deref(), Some("Fixture & page"));
+        assert_eq!(metadata.description.as_deref(), Some("Served by the tests"));
+        assert_eq!(metadata.canonical_url, Some(server.url("/canonical")));
+    }
+
+    #[actix_rt::test]
+    async fn respects_limits() {
+        let server = fixture_server();
+        let fetcher = fetcher(true);
+
+        // the title comes after the first max_size bytes
+        let metadata = fetch(&fetcher, &config(1024), &server.url("/large"))
+            .await
+            .unwrap();
+        assert_eq!(metadata.title, None);
+        let metadata = fetch(&fetcher, &config(32 * 1024), &server.url("/large"))
+            .await
+            .unwrap();
+        assert_eq!(metadata.title.as_deref(), Some("Too late"));
+
+        assert!(fetch(&fetcher, &config(1024), &server.url("/data.json"))
+            .await
+            .is_err());
+        assert!(fetch(&fetcher, &config(1024), &server.url("/slow"))
+            .await
+            .is_err());
+    }
+
+    #[actix_rt::test]
+    async fn rejects_private_addresses() {
+        let server = fixture_server();
+        let result = fetch(&fetcher(false), &config(1024), &server.url("/page")).await;
+        assert_eq!(result.unwrap_err(), FetchError::Blocked.to_string());
+    }
+}
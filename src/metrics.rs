@@ -0,0 +1,369 @@
+//! Prometheus metrics of the service.
+//!
+//! Requests are counted per route pattern like `/{short_code}` instead of per
+//! path, so that scrapers walking the code space cannot create new series.
+//! Lookups of short links count as redirect hits or misses. The connection
+//! pool reports the time it takes to get a connection through r2d2 events
+//! and blocking tasks (database queries, DNS lookups, file reads) are counted
+//! while they wait for a thread of `web::block`. Blocking tasks and created
+//! links are counted where they happen, outside of any request.
+//! See <https://prometheus.io/docs/instrumenting/exposition_formats/>
+
+use super::db::Pool;
+use super::response_types::RateLimitStats;
+use actix_web::{dev::ServiceResponse, error::BlockingError, web};
+use futures::Future;
+use r2d2::event::{CheckoutEvent, HandleEvent, TimeoutEvent};
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Display, Write};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Upper bounds of the buckets of request durations in seconds
+const REQUEST_BUCKETS: &[f64] = &[
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+/// Upper bounds of the buckets of connection pool wait times in seconds
+const POOL_WAIT_BUCKETS: &[f64] = &[
+    0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 30.0,
+];
+/// Methods counted by name, all others are counted as "OTHER"
+const METHODS: &[&str] = &["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE", "OPTIONS"];
+/// Routes of short link lookups, see server::redirect
+const REDIRECT_ROUTES: &[&str] = &["/{short_code}", "/{short_code}/{path:.*}"];
+
+/// Blocking tasks waiting for a thread and running, see block
+static BLOCKING_QUEUED: AtomicUsize = AtomicUsize::new(0);
+static BLOCKING_RUNNING: AtomicUsize = AtomicUsize::new(0);
+/// Links created, counted by the database queries storing them
+static LINKS_CREATED: AtomicU64 = AtomicU64::new(0);
+
+/// Counts of observed values per bucket, written cumulative like
+/// Prometheus expects them
+#[derive(Debug)]
+struct Histogram {
+    buckets: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(buckets: &'static [f64]) -> Histogram {
+        Histogram {
+            buckets,
+            counts: vec![0; buckets.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        if let Some(bucket) = self.buckets.iter().position(|le| value <= *le) {
+            self.counts[bucket] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    /// Writes the bucket, sum and count series of the histogram name
+    fn write(&self, out: &mut String, name: &str, labels: &str) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        let mut cumulative = 0;
+        for (le, count) in self.buckets.iter().zip(&self.counts) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}{}le=\"{}\"}} {}",
+                name, labels, separator, le, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}{}le=\"+Inf\"}} {}",
+            name, labels, separator, self.count
+        );
+        let _ = writeln!(
+            out,
+            "{} {}",
+            series(&format!("{}_sum", name), labels),
+            self.sum
+        );
+        let _ = writeln!(
+            out,
+            "{} {}",
+            series(&format!("{}_count", name), labels),
+            self.count
+        );
+    }
+}
+
+/// Escapes a label value
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Returns the name of a series with its labels, if any
+fn series(name: &str, labels: &str) -> String {
+    if labels.is_empty() {
+        name.to_owned()
+    } else {
+        format!("{}{{{}}}", name, labels)
+    }
+}
+
+/// Writes the HELP and TYPE lines of a metric
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Writes a metric with a single series without labels
+fn single(out: &mut String, name: &str, kind: &str, help: &str, value: impl Display) {
+    header(out, name, kind, help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// The metrics of the service, shared between all workers and the pool
+#[derive(Debug)]
+pub struct Metrics {
+    /// responses per route, method and status
+    responses: Mutex<BTreeMap<(String, String, u16), u64>>,
+    /// response times per route and method
+    durations: Mutex<BTreeMap<(String, String), Histogram>>,
+    redirect_hits: AtomicU64,
+    redirect_misses: AtomicU64,
+    pool_wait: Mutex<Histogram>,
+    pool_timeouts: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            responses: Mutex::new(BTreeMap::new()),
+            durations: Mutex::new(BTreeMap::new()),
+            redirect_hits: AtomicU64::new(0),
+            redirect_misses: AtomicU64::new(0),
+            pool_wait: Mutex::new(Histogram::new(POOL_WAIT_BUCKETS)),
+            pool_timeouts: AtomicU64::new(0),
+        }
+    }
+
+    /// Records a response that took elapsed to build. Requests that match
+    /// no route are counted as route "unmatched", so that the number of
+    /// series stays bounded.
+    pub fn record<B>(&self, response: &ServiceResponse<B>, elapsed: Duration) {
+        let req = response.request();
+        let route = req
+            .match_pattern()
+            .unwrap_or_else(|| String::from("unmatched"));
+        let method = match req.method().as_str() {
+            method if METHODS.contains(&method) => method.to_owned(),
+            _ => String::from("OTHER"),
+        };
+        let status = response.status();
+
+        if REDIRECT_ROUTES.contains(&route.as_str()) {
+            let counter = if status.is_client_error() {
+                &self.redirect_misses
+            } else {
+                &self.redirect_hits
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+
+        *self
+            .responses
+            .lock()
+            .unwrap()
+            .entry((route.clone(), method.clone(), status.as_u16()))
+            .or_insert(0) += 1;
+        self.durations
+            .lock()
+            .unwrap()
+            .entry((route, method))
+            .or_insert_with(|| Histogram::new(REQUEST_BUCKETS))
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// Renders all metrics in the Prometheus text format. database_size is
+    /// None if it could not be queried.
+    pub fn render(
+        &self,
+        pool: &Pool,
+        database_size: Option<i64>,
+        rate_limit: &RateLimitStats,
+    ) -> String {
+        let mut out = String::new();
+
+        let name = "k0r_http_requests_total";
+        header(
+            &mut out,
+            name,
+            "counter",
+            "Responses by route, method and status.",
+        );
+        for ((route, method, status), count) in self.responses.lock().unwrap().iter() {
+            let labels = format!(
+                "route=\"{}\",method=\"{}\",status=\"{}\"",
+                escape(route),
+                method,
+                status
+            );
+            let _ = writeln!(out, "{} {}", series(name, &labels), count);
+        }
+        let name = "k0r_http_request_duration_seconds";
+        header(
+            &mut out,
+            name,
+            "histogram",
+            "Response times by route and method.",
+        );
+        for ((route, method), histogram) in self.durations.lock().unwrap().iter() {
+            let labels = format!("route=\"{}\",method=\"{}\"", escape(route), method);
+            histogram.write(&mut out, name, &labels);
+        }
+
+        let name = "k0r_redirects_total";
+        header(&mut out, name, "counter", "Short link lookups by result.");
+        let hits = self.redirect_hits.load(Ordering::Relaxed);
+        let misses = self.redirect_misses.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}{{result=\"hit\"}} {}", name, hits);
+        let _ = writeln!(out, "{}{{result=\"miss\"}} {}", name, misses);
+        single(
+            &mut out,
+            "k0r_links_created_total",
+            "counter",
+            "Links created, not counting existing links returned instead.",
+            LINKS_CREATED.load(Ordering::Relaxed),
+        );
+
+        let state = pool.state();
+        let name = "k0r_db_pool_connections";
+        header(&mut out, name, "gauge", "Database connections by state.");
+        let in_use = state.connections - state.idle_connections;
+        let _ = writeln!(out, "{}{{state=\"idle\"}} {}", name, state.idle_connections);
+        let _ = writeln!(out, "{}{{state=\"in_use\"}} {}", name, in_use);
+        single(
+            &mut out,
+            "k0r_db_pool_max_connections",
+            "gauge",
+            "Maximum number of database connections.",
+            pool.max_size(),
+        );
+        let name = "k0r_db_pool_wait_seconds";
+        header(
+            &mut out,
+            name,
+            "histogram",
+            "Time it took to get a database connection.",
+        );
+        self.pool_wait.lock().unwrap().write(&mut out, name, "");
+        single(
+            &mut out,
+            "k0r_db_pool_timeouts_total",
+            "counter",
+            "Requests for a database connection that timed out.",
+            self.pool_timeouts.load(Ordering::Relaxed),
+        );
+
+        let name = "k0r_blocking_tasks";
+        header(&mut out, name, "gauge", "Blocking tasks by state.");
+        let queued = BLOCKING_QUEUED.load(Ordering::Relaxed);
+        let running = BLOCKING_RUNNING.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}{{state=\"queued\"}} {}", name, queued);
+        let _ = writeln!(out, "{}{{state=\"running\"}} {}", name, running);
+        if let Some(size) = database_size {
+            single(
+                &mut out,
+                "k0r_database_size_bytes",
+                "gauge",
+                "Size of the SQLite database file, without the WAL.",
+                size,
+            );
+        }
+
+        let gauges = [
+            (
+                "tracked_clients",
+                "Clients tracked by the rate limiter.",
+                rate_limit.tracked_clients,
+            ),
+            (
+                "banned_clients",
+                "Clients currently banned.",
+                rate_limit.banned_clients,
+            ),
+        ];
+        for (name, help, value) in gauges.iter() {
+            let name = format!("k0r_rate_limit_{}", name);
+            single(&mut out, &name, "gauge", help, value);
+        }
+        let counters = [
+            (
+                "limited",
+                "Lookups rejected because of the rate limit.",
+                rate_limit.limited,
+            ),
+            (
+                "banned",
+                "Clients banned for enumerating short codes.",
+                rate_limit.banned,
+            ),
+            (
+                "rejected",
+                "Lookups rejected because the client is banned.",
+                rate_limit.rejected,
+            ),
+        ];
+        for (name, help, value) in counters.iter() {
+            let name = format!("k0r_rate_limit_{}_total", name);
+            single(&mut out, &name, "counter", help, value);
+        }
+        out
+    }
+}
+
+/// Records the wait time of connection pool checkouts and timeouts,
+/// registered as event handler of the pool
+#[derive(Debug)]
+pub struct PoolEvents(pub Arc<Metrics>);
+
+impl HandleEvent for PoolEvents {
+    fn handle_checkout(&self, event: CheckoutEvent) {
+        let wait = event.duration().as_secs_f64();
+        self.0.pool_wait.lock().unwrap().observe(wait);
+    }
+
+    fn handle_timeout(&self, _event: TimeoutEvent) {
+        self.0.pool_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Counts newly created links, existing ones returned on deduplication
+/// don't count
+pub fn count_created(links: usize) {
+    LINKS_CREATED.fetch_add(links as u64, Ordering::Relaxed);
+}
+
+/// Runs f on the thread pool of web::block, counting the tasks that wait
+/// for a thread and the ones running
+pub fn block<F, I, E>(f: F) -> impl Future<Output = Result<I, BlockingError<E>>>
+where
+    F: FnOnce() -> Result<I, E> + Send + 'static,
+    I: Send + 'static,
+    E: Send + Debug + 'static,
+{
+    BLOCKING_QUEUED.fetch_add(1, Ordering::Relaxed);
+    web::block(move || {
+        BLOCKING_QUEUED.fetch_sub(1, Ordering::Relaxed);
+        BLOCKING_RUNNING.fetch_add(1, Ordering::Relaxed);
+        let result = f();
+        BLOCKING_RUNNING.fetch_sub(1, Ordering::Relaxed);
+        result
+    })
+}
@@ -0,0 +1,140 @@
+//! Optional normalization of link targets, so that the same page is stored
+//! the same way regardless of how it was linked.
+//!
+//! Parsing a URL already lowercases the host, converts internationalized
+//! domain names to punycode and drops default ports. On top of that,
+//! configured tracking parameters are removed and the remaining query
+//! parameters can be sorted.
+
+use super::config::NormalizeConfig;
+use url::form_urlencoded;
+use url::Url;
+
+/// Checks if the query parameter name matches pattern,
+/// which may end with * to match all names with that prefix
+fn matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name
+            .get(..prefix.len())
+            .map(|start| start.eq_ignore_ascii_case(prefix))
+            .unwrap_or(false),
+        None => name.eq_ignore_ascii_case(pattern),
+    }
+}
+
+/// Returns the decoded name of a raw query parameter like `utm_source=x`
+fn param_name(param: &str) -> String {
+    let name = param.split('=').next().unwrap_or("");
+    form_urlencoded::parse(name.as_bytes())
+        .next()
+        .map(|(name, _)| name.into_owned())
+        .unwrap_or_default()
+}
+
+/// Normalizes url as configured. Parameters are kept in their original
+/// encoding, only their order changes if sorting is enabled.
+pub fn normalize(url: &Url, config: &NormalizeConfig) -> Url {
+    let mut url = url.clone();
+
+    if let Some(query) = url.query() {
+        let mut params = query
+            .split('&')
+            .filter(|param| !param.is_empty())
+            .map(|param| (param_name(param), param.to_owned()))
+            .filter(|(name, _)| {
+                !config
+                    .strip_params
+                    .iter()
+                    .any(|pattern| matches(pattern, name))
+            })
+            .collect::<Vec<(String, String)>>();
+
+        if config.sort_params {
+            // stable, so that repeated parameters keep their order
+            params.sort_by(|(a, _), (b, _)| a.cmp(b));
+        }
+
+        let query = params
+            .into_iter()
+            .map(|(_, param)| param)
+            .collect::<Vec<String>>()
+            .join("&");
+        url.set_query(if query.is_empty() { None } else { Some(&query) });
+    }
+
+    url
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalized(url: &str, sort_params: bool) -> String {
+        let config = NormalizeConfig {
+            enabled: true,
+            sort_params,
+            ..NormalizeConfig::default()
+        };
+        normalize(&Url::parse(url).unwrap(), &config).to_string()
+    }
+
+    #[test]
+    fn matches_patterns() {
+        assert!(matches("utm_*", "utm_source"));
+        assert!(matches("utm_*", "UTM_Medium"));
+        assert!(matches("fbclid", "FBCLID"));
+        assert!(!matches("utm_*", "utm"));
+        assert!(!matches("fbclid", "fbclid2"));
+        // names with multi-byte characters at the end of the prefix
+        assert!(!matches("utm_*", "utmü"));
+    }
+
+    #[test]
+    fn strips_tracking_params() {
+        assert_eq!(
+            normalized("https://example.com/?id=1&utm_source=x&fbclid=y#top", false),
+            "https://example.com/?id=1#top"
+        );
+        // names are compared decoded
+        assert_eq!(
+            normalized("https://example.com/?utm%5Fsource=x&id=1", false),
+            "https://example.com/?id=1"
+        );
+        assert_eq!(
+            normalized("https://example.com/a?utm_source=x&&gclid=y", false),
+            "https://example.com/a"
+        );
+    }
+
+    #[test]
+    fn sorts_params_stably() {
+        assert_eq!(
+            normalized("https://example.com/?b=2&a=2&b=1&a=1", true),
+            "https://example.com/?a=2&a=1&b=2&b=1"
+        );
+        assert_eq!(
+            normalized("https://example.com/?b=2&a=1", false),
+            "https://example.com/?b=2&a=1"
+        );
+    }
+
+    #[test]
+    fn keeps_the_encoding_of_params() {
+        assert_eq!(
+            normalized("https://example.com/?q=a+b%26c&p=%C3%BC", true),
+            "https://example.com/?p=%C3%BC&q=a+b%26c"
+        );
+    }
+
+    #[test]
+    fn relies_on_the_parser_for_hosts_and_ports() {
+        assert_eq!(
+            normalized("HTTPS://ExAmple.COM:443/Path", false),
+            "https://example.com/Path"
+        );
+        assert_eq!(
+            normalized("https://bücher.example/", false),
+            "https://xn--bcher-kva.example/"
+        );
+    }
+}
@@ -0,0 +1,1109 @@
+//! OpenAPI 3 document of the service, served at `GET /api/v1/openapi.json`
+//! and rendered as docs page at `GET /api/v1/docs`.
+//!
+//! Schemas are derived with schemars from the same serde types that the
+//! handlers read and write, so they cannot drift apart. Error bodies are
+//! built by response_types::Error, their schemas come from ErrorBody and
+//! ProblemBody. The server tests check real responses against the document.
+
+use super::api::ListQuery;
+use super::config::Config;
+use super::db::{LinkPostData, UrlPatchData, UrlPostData};
+use super::health::Health;
+use super::qr::QrQuery;
+use super::redirect::{RedirectMode, UnfurlMode};
+use super::response_types::{
+    BulkResult, Error, FieldError, LinkList, LinkResponse, Liveness, ProbeCheck, RateLimitStats,
+    Readiness, RecheckResult, Status,
+};
+use super::server::{SearchQuery, ShortenForm};
+use schemars::{
+    gen::{SchemaGenerator, SchemaSettings},
+    JsonSchema,
+};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+
+/// A type with an example value, shown in the document and on the docs page
+pub trait Example: Serialize + Sized {
+    fn example() -> Self;
+}
+
+/// The body of a request or response
+#[derive(Clone, Copy, PartialEq)]
+pub enum Media {
+    /// JSON with the named schema, `[]` suffixed for arrays
+    Json(&'static str),
+    /// form data with the named schema
+    Form(&'static str),
+    /// any other media type, like `text/html`
+    Other(&'static str),
+}
+
+impl Media {
+    pub fn media_type(self) -> &'static str {
+        match self {
+            Media::Json(_) => "application/json",
+            Media::Form(_) => "application/x-www-form-urlencoded",
+            Media::Other(media_type) => media_type,
+        }
+    }
+
+    pub fn is_json(self) -> bool {
+        matches!(self, Media::Json(_))
+    }
+
+    pub fn schema(self) -> Option<&'static str> {
+        match self {
+            Media::Json(schema) | Media::Form(schema) => Some(schema),
+            Media::Other(_) => None,
+        }
+    }
+}
+
+/// An endpoint of the service, see ENDPOINTS
+pub struct Endpoint {
+    pub method: &'static str,
+    /// the path with parameters in braces, like `/api/v1/links/{short_code}`
+    pub path: &'static str,
+    pub summary: &'static str,
+    /// requires an API key, sent as `Authorization: Bearer $key`
+    pub auth: bool,
+    /// schema of the query parameters
+    pub query: Option<&'static str>,
+    /// the accepted request bodies, if any
+    pub body: &'static [Media],
+    /// status, description and body of the responses. A status is listed
+    /// once per body, with the same description.
+    pub responses: &'static [(u16, &'static str, Option<Media>)],
+}
+
+const ERROR: Option<Media> = Some(Media::Json("Error"));
+const HTML: Option<Media> = Some(Media::Other("text/html"));
+const SVG: Option<Media> = Some(Media::Other("image/svg+xml"));
+const PNG: Option<Media> = Some(Media::Other("image/png"));
+
+/// Every endpoint of the service, in the order of the docs page
+pub const ENDPOINTS: &[Endpoint] = &[
+    Endpoint {
+        method: "post",
+        path: "/api/v1/links",
+        summary: "Creates a link",
+        auth: true,
+        query: None,
+        body: &[Media::Json("LinkPostData")],
+        responses: &[
+            (201, "The created link", Some(Media::Json("LinkResponse"))),
+            (
+                200,
+                "An existing link to the same target, with deduplicate enabled",
+                Some(Media::Json("LinkResponse")),
+            ),
+            (400, "Invalid target, name or domain", ERROR),
+            (401, "Invalid API key", ERROR),
+            (403, "Domain not allowed for the user", ERROR),
+            (409, "Name already taken", ERROR),
+        ],
+    },
+    Endpoint {
+        method: "post",
+        path: "/api/v1/links/bulk",
+        summary: "Creates many links at once",
+        auth: true,
+        query: None,
+        body: &[
+            Media::Json("LinkPostData[]"),
+            Media::Other("application/x-ndjson"),
+        ],
+        responses: &[
+            (
+                200,
+                "One result per entry",
+                Some(Media::Json("BulkResult[]")),
+            ),
+            (400, "Invalid body or too many entries", ERROR),
+            (401, "Invalid API key", ERROR),
+        ],
+    },
+    Endpoint {
+        method: "get",
+        path: "/api/v1/links",
+        summary: "Lists the links of the user, ordered by creation",
+        auth: true,
+        query: Some("ListQuery"),
+        body: &[],
+        responses: &[
+            (200, "A page of links", Some(Media::Json("LinkList"))),
+            (400, "Invalid query", ERROR),
+            (401, "Invalid API key", ERROR),
+        ],
+    },
+    Endpoint {
+        method: "get",
+        path: "/api/v1/links/{short_code}",
+        summary: "Fetches a single link",
+        auth: false,
+        query: None,
+        body: &[],
+        responses: &[
+            (200, "The link", Some(Media::Json("LinkResponse"))),
+            (404, "No such link", ERROR),
+        ],
+    },
+    Endpoint {
+        method: "patch",
+        path: "/api/v1/links/{short_code}",
+        summary: "Updates a link of the user, null resets a field to its default",
+        auth: true,
+        query: None,
+        body: &[Media::Json("UrlPatchData")],
+        responses: &[
+            (200, "The updated link", Some(Media::Json("LinkResponse"))),
+            (400, "Invalid target", ERROR),
+            (401, "Invalid API key", ERROR),
+            (
+                403,
+                "Link of another user, for users that are not admins",
+                ERROR,
+            ),
+            (404, "No such link", ERROR),
+        ],
+    },
+    Endpoint {
+        method: "delete",
+        path: "/api/v1/links/{short_code}",
+        summary: "Deletes a link of the user, its short code is not reused",
+        auth: true,
+        query: None,
+        body: &[],
+        responses: &[
+            (204, "The link was deleted", None),
+            (401, "Invalid API key", ERROR),
+            (
+                403,
+                "Link of another user, for users that are not admins",
+                ERROR,
+            ),
+            (404, "No such link", ERROR),
+        ],
+    },
+    Endpoint {
+        method: "get",
+        path: "/api/v1/admin/links/broken",
+        summary: "Lists the links of all users whose last health check failed, for admins only",
+        auth: true,
+        query: Some("ListQuery"),
+        body: &[],
+        responses: &[
+            (200, "A page of links", Some(Media::Json("LinkList"))),
+            (400, "Invalid query", ERROR),
+            (401, "Invalid API key", ERROR),
+            (403, "Not an admin", ERROR),
+        ],
+    },
+    Endpoint {
+        method: "post",
+        path: "/api/v1/admin/domains/reload",
+        summary: "Reloads the domain policy and checks all links against it, for admins only",
+        auth: true,
+        query: None,
+        body: &[],
+        responses: &[
+            (
+                200,
+                "The number of newly blocked and unblocked links",
+                Some(Media::Json("RecheckResult")),
+            ),
+            (400, "Invalid domain policy file", ERROR),
+            (401, "Invalid API key", ERROR),
+            (403, "Not an admin", ERROR),
+        ],
+    },
+    Endpoint {
+        method: "get",
+        path: "/api/v1/admin/rate-limit",
+        summary: "Returns the counters of the rate limiter, for admins only",
+        auth: true,
+        query: None,
+        body: &[],
+        responses: &[
+            (200, "The counters", Some(Media::Json("RateLimitStats"))),
+            (401, "Invalid API key", ERROR),
+            (403, "Not an admin", ERROR),
+        ],
+    },
+    Endpoint {
+        method: "get",
+        path: "/api/v1/openapi.json",
+        summary: "Returns this document",
+        auth: false,
+        query: None,
+        body: &[],
+        responses: &[(
+            200,
+            "The OpenAPI document",
+            Some(Media::Other("application/json")),
+        )],
+    },
+    Endpoint {
+        method: "get",
+        path: "/api/v1/docs",
+        summary: "Shows the endpoints and schemas of this document as HTML page",
+        auth: false,
+        query: None,
+        body: &[],
+        responses: &[(200, "The docs page", HTML)],
+    },
+    Endpoint {
+        method: "get",
+        path: "/",
+        summary: "Shows the index page with the form to shorten links, \
+                  searches redirect to the preview of the searched link",
+        auth: false,
+        query: Some("SearchQuery"),
+        body: &[],
+        responses: &[
+            (200, "The index page", HTML),
+            (303, "Redirect to the preview of the searched link", None),
+        ],
+    },
+    Endpoint {
+        method: "post",
+        path: "/",
+        summary: "Creates a link, with the API key in the body. Form data is sent by the \
+                  index page and gets HTML pages in response (legacy)",
+        auth: false,
+        query: None,
+        body: &[Media::Json("UrlPostData"), Media::Form("ShortenForm")],
+        responses: &[
+            (201, "The link was created", Some(Media::Json("Status"))),
+            (201, "The link was created", HTML),
+            (
+                200,
+                "An existing link to the same target, with deduplicate enabled",
+                Some(Media::Json("Status")),
+            ),
+            (
+                200,
+                "An existing link to the same target, with deduplicate enabled",
+                HTML,
+            ),
+            (400, "Invalid target", ERROR),
+            (400, "Invalid target", HTML),
+            (401, "Invalid API key", ERROR),
+            (401, "Invalid API key", HTML),
+            (403, "Domain not allowed for the user", ERROR),
+            (403, "Domain not allowed for the user", HTML),
+        ],
+    },
+    Endpoint {
+        method: "get",
+        path: "/{short_code}",
+        summary: "Redirects to the target of a short link or named link",
+        auth: false,
+        query: None,
+        body: &[],
+        responses: &[
+            (301, "Redirect to the target, with redirect mode 301", HTML),
+            (
+                302,
+                "Redirect to the target, with redirect mode 302, the default",
+                HTML,
+            ),
+            (307, "Redirect to the target, with redirect mode 307", HTML),
+            (308, "Redirect to the target, with redirect mode 308", HTML),
+            (200, "Interstitial or link card page", HTML),
+            (404, "No such link", ERROR),
+            (410, "The link was blocked", ERROR),
+            (429, "Too many lookups, see the Retry-After header", ERROR),
+        ],
+    },
+    Endpoint {
+        method: "get",
+        path: "/{short_code}/{path}",
+        summary: "Redirects to the target of a short link with passthrough, a name with \
+                  several segments or a template, with the rest of the path appended or \
+                  filled in",
+        auth: false,
+        query: None,
+        body: &[],
+        responses: &[
+            (301, "Redirect to the target, with redirect mode 301", HTML),
+            (
+                302,
+                "Redirect to the target, with redirect mode 302, the default",
+                HTML,
+            ),
+            (307, "Redirect to the target, with redirect mode 307", HTML),
+            (308, "Redirect to the target, with redirect mode 308", HTML),
+            (200, "Interstitial or link card page", HTML),
+            (
+                400,
+                "Invalid value for a placeholder of the template",
+                ERROR,
+            ),
+            (404, "No such link", ERROR),
+            (410, "The link was blocked", ERROR),
+            (429, "Too many lookups, see the Retry-After header", ERROR),
+        ],
+    },
+    Endpoint {
+        method: "get",
+        path: "/{short_code}+",
+        summary: "Shows where a short link or named link leads to",
+        auth: false,
+        query: None,
+        body: &[],
+        responses: &[
+            (200, "The preview page", HTML),
+            (404, "No such link", ERROR),
+            (410, "The link was blocked", ERROR),
+        ],
+    },
+    Endpoint {
+        method: "get",
+        path: "/{short_code}/preview",
+        summary: "Shows where a short link leads to, like /{short_code}+",
+        auth: false,
+        query: None,
+        body: &[],
+        responses: &[
+            (200, "The preview page", HTML),
+            (404, "No such link", ERROR),
+            (410, "The link was blocked", ERROR),
+        ],
+    },
+    Endpoint {
+        method: "get",
+        path: "/{short_code}.{format}",
+        summary: "Returns the QR code of a short link or named link, format is svg or png",
+        auth: false,
+        query: Some("QrQuery"),
+        body: &[],
+        responses: &[
+            (200, "The QR code", SVG),
+            (200, "The QR code", PNG),
+            (400, "Invalid options", ERROR),
+            (404, "No such link", ERROR),
+        ],
+    },
+    Endpoint {
+        method: "get",
+        path: "/{short_code}/qr",
+        summary: "Returns the QR code of a short link, as SVG unless format is png",
+        auth: false,
+        query: Some("QrQuery"),
+        body: &[],
+        responses: &[
+            (200, "The QR code", SVG),
+            (200, "The QR code", PNG),
+            (400, "Invalid options", ERROR),
+            (404, "No such link", ERROR),
+        ],
+    },
+    Endpoint {
+        method: "get",
+        path: "/static/{filename}",
+        summary: "Returns a static file like the stylesheet",
+        auth: false,
+        query: None,
+        body: &[],
+        responses: &[
+            (200, "The file", Some(Media::Other("*/*"))),
+            (404, "No such file", None),
+        ],
+    },
+    Endpoint {
+        method: "get",
+        path: "/metrics",
+        summary: "Returns the metrics in the Prometheus text format, if enabled. The path is \
+                  configurable and the metrics may be served on their own address instead",
+        auth: false,
+        query: None,
+        body: &[],
+        responses: &[(200, "The metrics", Some(Media::Other("text/plain")))],
+    },
+    Endpoint {
+        method: "get",
+        path: "/healthz",
+        summary: "Liveness probe, succeeds as long as the service handles requests",
+        auth: false,
+        query: None,
+        body: &[],
+        responses: &[(200, "The service is alive", Some(Media::Json("Liveness")))],
+    },
+    Endpoint {
+        method: "get",
+        path: "/readyz",
+        summary: "Readiness probe, checks the database connection, schema and disk",
+        auth: false,
+        query: None,
+        body: &[],
+        responses: &[
+            (200, "All checks passed", Some(Media::Json("Readiness"))),
+            (
+                503,
+                "A check failed or the service is shutting down",
+                Some(Media::Json("Readiness")),
+            ),
+        ],
+    },
+];
+
+impl Example for LinkPostData {
+    fn example() -> Self {
+        LinkPostData {
+            url: String::from("https://example.com/article"),
+            title: Some(String::from("An example")),
+            description: Some(String::from("An example article")),
+            redirect: Some(RedirectMode::Found),
+            image: Some(String::from("https://example.com/card.png")),
+            unfurl: Some(UnfurlMode::Crawlers),
+            passthrough: Some(false),
+            name: Some(String::from("example")),
+            domain: Some(String::from("go.example.com")),
+        }
+    }
+}
+
+impl Example for UrlPostData {
+    fn example() -> Self {
+        UrlPostData {
+            link: LinkPostData::example(),
+            key: String::from("859b397c-a933-461d-a9b1-86dd20084c02"),
+        }
+    }
+}
+
+impl Example for ShortenForm {
+    fn example() -> Self {
+        ShortenForm {
+            url: String::from("https://example.com/article"),
+            title: String::from("An example"),
+            description: String::new(),
+            key: String::from("859b397c-a933-461d-a9b1-86dd20084c02"),
+        }
+    }
+}
+
+impl Example for UrlPatchData {
+    fn example() -> Self {
+        UrlPatchData {
+            url: Some(String::from("https://example.com/article")),
+            title: Some(String::from("An example")),
+            description: None,
+            redirect: Some(Some(RedirectMode::MovedPermanently)),
+            image: Some(None),
+            unfurl: Some(Some(UnfurlMode::Always)),
+            passthrough: Some(true),
+        }
+    }
+}
+
+impl Example for ListQuery {
+    fn example() -> Self {
+        ListQuery {
+            cursor: Some(String::from("1z5")),
+            limit: Some(50),
+        }
+    }
+}
+
+impl Example for SearchQuery {
+    fn example() -> Self {
+        SearchQuery {
+            q: Some(String::from("1z5")),
+        }
+    }
+}
+
+impl Example for QrQuery {
+    fn example() -> Self {
+        QrQuery {
+            size: Some(512),
+            margin: Some(2),
+            ec: Some(String::from("H")),
+            fg: Some(String::from("345")),
+            bg: Some(String::from("ffffff")),
+            format: Some(String::from("png")),
+        }
+    }
+}
+
+impl Example for Health {
+    fn example() -> Self {
+        Health {
+            status: Some(200),
+            final_url: Some(String::from("https://example.com/article")),
+            error: None,
+            checked_at: String::from("2021-05-01T12:00:00Z"),
+            failures: 0,
+        }
+    }
+}
+
+impl Example for LinkResponse {
+    fn example() -> Self {
+        LinkResponse {
+            code: String::from("1z5"),
+            short_url: String::from("https://go.example.com/example"),
+            target: String::from("https://example.com/article"),
+            title: String::from("An example"),
+            description: String::from("An example article"),
+            created_at: String::from("2021-05-01T12:00:00Z"),
+            visits: 42,
+            redirect: Some(RedirectMode::Found),
+            image: Some(String::from("https://example.com/card.png")),
+            unfurl: Some(UnfurlMode::Crawlers),
+            canonical_url: Some(String::from("https://example.com/article")),
+            health: Some(Health::example()),
+            blocked_at: None,
+            passthrough: false,
+            name: Some(String::from("example")),
+            domain: Some(String::from("go.example.com")),
+            created: Some(true),
+        }
+    }
+}
+
+impl Example for LinkList {
+    fn example() -> Self {
+        LinkList {
+            links: vec![LinkResponse::example()],
+            next_cursor: Some(String::from("1z5")),
+        }
+    }
+}
+
+impl Example for BulkResult {
+    fn example() -> Self {
+        BulkResult::failed(
+            1,
+            Error::bad_request("invalid_url", "Invalid URL").on_field("url"),
+        )
+    }
+}
+
+impl Example for Status {
+    fn example() -> Self {
+        Status {
+            status: "ok",
+            message: String::from("1z5"),
+            url: String::from("https://example.com/article"),
+            created: true,
+        }
+    }
+}
+
+impl Example for RecheckResult {
+    fn example() -> Self {
+        RecheckResult {
+            blocked: 3,
+            unblocked: 1,
+        }
+    }
+}
+
+impl Example for Liveness {
+    fn example() -> Self {
+        Liveness { status: "ok" }
+    }
+}
+
+impl Example for Readiness {
+    fn example() -> Self {
+        let checks = vec![
+            ("connection", ProbeCheck::new(Ok(()))),
+            ("schema", ProbeCheck::new(Ok(()))),
+            (
+                "disk",
+                ProbeCheck::new(Err(String::from("Read-only file system (os error 30)"))),
+            ),
+            ("shutdown", ProbeCheck::new(Ok(()))),
+        ];
+        Readiness {
+            status: "not_ready",
+            checks: checks.into_iter().collect(),
+        }
+    }
+}
+
+impl Example for RateLimitStats {
+    fn example() -> Self {
+        RateLimitStats {
+            tracked_clients: 12,
+            banned_clients: 1,
+            limited: 30,
+            banned: 2,
+            rejected: 120,
+        }
+    }
+}
+
+/// JSON body of error responses, see Error::to_json. Some errors add
+/// details like the rejected domain as additional properties.
+#[derive(JsonSchema)]
+#[schemars(rename = "Error")]
+#[allow(dead_code)] // only its schema is used
+struct ErrorBody {
+    /// a generic message
+    error: String,
+    /// stable error code like "invalid_url", which clients can match on
+    code: String,
+    /// the fields of the request that failed validation
+    #[serde(default)]
+    errors: Vec<FieldError>,
+}
+
+/// RFC 7807 problem details of error responses, sent instead of Error
+/// to clients that accept `application/problem+json`, see Error::to_problem
+#[derive(JsonSchema)]
+#[schemars(rename = "Problem")]
+#[allow(dead_code)] // only its schema is used
+struct ProblemBody {
+    /// the error code as URN, like `urn:k0r:error:invalid_url`
+    #[serde(rename = "type")]
+    kind: String,
+    /// a generic message
+    title: String,
+    status: u16,
+    code: String,
+    #[serde(default)]
+    errors: Vec<FieldError>,
+}
+
+/// Adds the schema of T to gen and returns its name with example
+fn with_example<T: JsonSchema>(gen: &mut SchemaGenerator, example: Value) -> (String, Value) {
+    gen.subschema_for::<T>();
+    (T::schema_name(), example)
+}
+
+/// Like with_example, for types with an example value
+fn example<T: JsonSchema + Example>(gen: &mut SchemaGenerator) -> (String, Value) {
+    with_example::<T>(gen, serde_json::to_value(T::example()).unwrap())
+}
+
+/// Moves references that have siblings like nullable into allOf,
+/// because siblings of $ref are ignored in OpenAPI 3.0
+fn wrap_refs(schema: &mut Value) {
+    match schema {
+        Value::Object(object) => {
+            if object.len() > 1 {
+                if let Some(reference) = object.remove("$ref") {
+                    object.insert(String::from("allOf"), json!([{ "$ref": reference }]));
+                }
+            }
+            object.values_mut().for_each(wrap_refs);
+        }
+        Value::Array(items) => items.iter_mut().for_each(wrap_refs),
+        _ => {}
+    }
+}
+
+/// Returns the named schemas of all request and response types, followed
+/// by the schemas they refer to
+pub fn schemas() -> Vec<(String, Value)> {
+    let mut gen = SchemaSettings::openapi3().into_generator();
+    let error = Error::unknown_domain("go.example.com").on_field("domain");
+    let examples = vec![
+        example::<LinkPostData>(&mut gen),
+        example::<UrlPostData>(&mut gen),
+        example::<ShortenForm>(&mut gen),
+        example::<UrlPatchData>(&mut gen),
+        example::<ListQuery>(&mut gen),
+        example::<SearchQuery>(&mut gen),
+        example::<QrQuery>(&mut gen),
+        example::<LinkResponse>(&mut gen),
+        example::<Health>(&mut gen),
+        example::<LinkList>(&mut gen),
+        example::<BulkResult>(&mut gen),
+        example::<Status>(&mut gen),
+        example::<RecheckResult>(&mut gen),
+        example::<RateLimitStats>(&mut gen),
+        example::<Liveness>(&mut gen),
+        example::<Readiness>(&mut gen),
+        with_example::<ErrorBody>(&mut gen, error.to_json()),
+        with_example::<ProblemBody>(&mut gen, error.to_problem()),
+    ];
+
+    let mut definitions = gen
+        .take_definitions()
+        .into_iter()
+        .map(|(name, schema)| {
+            let mut schema = serde_json::to_value(schema).unwrap();
+            wrap_refs(&mut schema);
+            (name, schema)
+        })
+        .collect::<Map<String, Value>>();
+
+    let mut schemas = examples
+        .into_iter()
+        .filter_map(|(name, example)| {
+            let mut schema = definitions.remove(&name)?;
+            schema["example"] = example;
+            Some((name, schema))
+        })
+        .collect::<Vec<(String, Value)>>();
+    schemas.extend(definitions);
+
+    // details of errors are additional properties
+    for (name, schema) in schemas.iter_mut() {
+        if name == "Error" || name == "Problem" {
+            schema["additionalProperties"] = json!(true);
+        }
+    }
+    schemas
+}
+
+/// A property of a schema, as shown on the docs page
+pub struct Field {
+    pub name: String,
+    pub kind: String,
+    pub required: bool,
+}
+
+/// A schema with its properties and example, as shown on the docs page.
+/// The example is empty for objects without one.
+pub struct SchemaDoc {
+    pub name: String,
+    pub fields: Vec<Field>,
+    pub example: String,
+}
+
+/// Describes the type of a property, like `string`, `LinkResponse[]`
+/// or `"always" | "never"`
+fn type_name(schema: &Value) -> String {
+    let name = if let Some(name) = schema["$ref"].as_str() {
+        name.rsplit('/').next().unwrap_or(name).to_owned()
+    } else if schema["allOf"].is_array() {
+        type_name(&schema["allOf"][0])
+    } else if let Some(schemas) = schema["oneOf"].as_array() {
+        let names = schemas.iter().map(type_name).collect::<Vec<String>>();
+        names.join(" | ")
+    } else if let Some(values) = schema["enum"].as_array() {
+        let values = values.iter().map(Value::to_string).collect::<Vec<String>>();
+        values.join(" | ")
+    } else {
+        match schema["type"].as_str() {
+            Some("array") => format!("{}[]", type_name(&schema["items"])),
+            Some("object") if schema["additionalProperties"].is_object() => {
+                format!("object of {}", type_name(&schema["additionalProperties"]))
+            }
+            Some(kind) => kind.to_owned(),
+            None => String::from("any"),
+        }
+    };
+    match schema["nullable"].as_bool() {
+        Some(true) => format!("{} or null", name),
+        _ => name,
+    }
+}
+
+/// Returns all schemas with their properties, for the docs page
+pub fn schema_docs() -> Vec<SchemaDoc> {
+    schemas()
+        .into_iter()
+        .map(|(name, schema)| {
+            let required = schema["required"].as_array().cloned().unwrap_or_default();
+            let fields = schema["properties"]
+                .as_object()
+                .into_iter()
+                .flatten()
+                .map(|(field, property)| Field {
+                    name: field.clone(),
+                    kind: type_name(property),
+                    required: required.contains(&json!(field)),
+                })
+                .collect();
+            // referenced types like enums have no example, but their values
+            let example = match &schema["example"] {
+                Value::Null if schema["type"] == "object" => String::new(),
+                Value::Null => type_name(&schema),
+                example => serde_json::to_string_pretty(example).unwrap_or_default(),
+            };
+            SchemaDoc {
+                name,
+                fields,
+                example,
+            }
+        })
+        .collect()
+}
+
+/// References a schema by name, see Media::Json
+fn schema_ref(name: &str) -> Value {
+    match name.strip_suffix("[]") {
+        Some(name) => json!({ "type": "array", "items": schema_ref(name) }),
+        None => json!({ "$ref": format!("#/components/schemas/{}", name) }),
+    }
+}
+
+/// Describes content as OpenAPI media type object
+fn media_type(content: Media) -> Value {
+    match content.schema() {
+        Some(schema) => json!({ "schema": schema_ref(schema) }),
+        None => json!({}),
+    }
+}
+
+/// Describes an endpoint as OpenAPI operation
+fn operation(endpoint: &Endpoint, schemas: &Map<String, Value>) -> Value {
+    let mut parameters = endpoint
+        .path
+        .split('{')
+        .skip(1)
+        .filter_map(|part| Some(part.split_once('}')?.0))
+        .map(|name| json!({ "name": name, "in": "path", "required": true, "schema": { "type": "string" } }))
+        .collect::<Vec<Value>>();
+    if let Some(query) = endpoint.query.and_then(|name| schemas.get(name)) {
+        let required = query["required"].as_array().cloned().unwrap_or_default();
+        for (name, schema) in query["properties"].as_object().into_iter().flatten() {
+            parameters.push(json!({
+                "name": name,
+                "in": "query",
+                "required": required.contains(&json!(name)),
+                "schema": schema,
+            }));
+        }
+    }
+
+    // every endpoint that can fail may also find the database unavailable
+    let unavailable = (503, "Database unavailable", ERROR);
+    let can_fail = endpoint
+        .responses
+        .iter()
+        .any(|(_, _, content)| *content == ERROR);
+    let mut responses = Map::new();
+    for (status, description, content) in endpoint
+        .responses
+        .iter()
+        .chain(Some(&unavailable).filter(|_| can_fail))
+    {
+        let response = responses
+            .entry(status.to_string())
+            .or_insert_with(|| json!({ "description": description }));
+        if let Some(content) = *content {
+            response["content"][content.media_type()] = media_type(content);
+        }
+        // errors are RFC 7807 problem details if the client asks for them,
+        // and pages for browsers outside of the API, see error_page.rs
+        if *content == ERROR {
+            response["content"]["application/problem+json"] = media_type(Media::Json("Problem"));
+            if !endpoint.path.starts_with("/api/") {
+                response["content"]["text/html"] = json!({});
+            }
+        }
+    }
+
+    let mut operation = json!({
+        "summary": endpoint.summary,
+        "parameters": parameters,
+        "responses": responses,
+    });
+    if !endpoint.body.is_empty() {
+        let content = endpoint
+            .body
+            .iter()
+            .map(|content| (String::from(content.media_type()), media_type(*content)))
+            .collect::<Map<String, Value>>();
+        operation["requestBody"] = json!({ "required": true, "content": content });
+    }
+    if endpoint.auth {
+        operation["security"] = json!([{ "apiKey": [] }]);
+    }
+    operation
+}
+
+/// Builds the OpenAPI document of all ENDPOINTS
+pub fn document(config: &Config) -> Value {
+    let schemas = schemas().into_iter().collect::<Map<String, Value>>();
+
+    let mut paths = Map::new();
+    for endpoint in ENDPOINTS {
+        let path = paths.entry(endpoint.path).or_insert_with(|| json!({}));
+        path[endpoint.method] = operation(endpoint, &schemas);
+    }
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "k0r",
+            "description": env!("CARGO_PKG_DESCRIPTION"),
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "servers": [{ "url": config.base_url }],
+        "paths": paths,
+        "components": {
+            "schemas": schemas,
+            "securitySchemes": {
+                "apiKey": { "type": "http", "scheme": "bearer" },
+            },
+        },
+    })
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// Checks value against schema, following references into document.
+    /// Supports the subset of OpenAPI schemas that schemars generates, and
+    /// rejects properties that are not in the schema unless it allows
+    /// additional properties.
+    fn check(document: &Value, schema: &Value, value: &Value, at: &str) -> Result<(), String> {
+        if let Some(reference) = schema["$ref"].as_str() {
+            let name = reference.trim_start_matches("#/components/schemas/");
+            let schema = &document["components"]["schemas"][name];
+            return check(document, schema, value, at);
+        }
+        if value.is_null() {
+            return match schema["nullable"].as_bool() {
+                Some(true) => Ok(()),
+                _ => Err(format!("{} is null", at)),
+            };
+        }
+        for schema in schema["allOf"].as_array().into_iter().flatten() {
+            check(document, schema, value, at)?;
+        }
+        if let Some(schemas) = schema["oneOf"].as_array() {
+            let matching = schemas
+                .iter()
+                .filter(|schema| check(document, schema, value, at).is_ok())
+                .count();
+            if matching != 1 {
+                return Err(format!("{} matches {} schemas of oneOf", at, matching));
+            }
+        }
+        if let Some(values) = schema["enum"].as_array() {
+            if !values.contains(value) {
+                return Err(format!("{} is {}, not one of {:?}", at, value, values));
+            }
+        }
+
+        let is_valid = match schema["type"].as_str() {
+            Some("object") => {
+                let object = value.as_object().ok_or(format!("{} is no object", at))?;
+                for name in schema["required"].as_array().into_iter().flatten() {
+                    let name = name.as_str().unwrap_or_default();
+                    if !object.contains_key(name) {
+                        return Err(format!("{}.{} is missing", at, name));
+                    }
+                }
+                for (name, value) in object {
+                    let at = format!("{}.{}", at, name);
+                    match (&schema["properties"][name], &schema["additionalProperties"]) {
+                        (Value::Null, Value::Bool(true)) => {}
+                        (Value::Null, Value::Null) => return Err(format!("{} is unknown", at)),
+                        (Value::Null, schema) | (schema, _) => check(document, schema, value, &at)?,
+                    }
+                }
+                true
+            }
+            Some("array") => {
+                let items = value.as_array().ok_or(format!("{} is no array", at))?;
+                for (index, item) in items.iter().enumerate() {
+                    check(
+                        document,
+                        &schema["items"],
+                        item,
+                        &format!("{}[{}]", at, index),
+                    )?;
+                }
+                true
+            }
+            Some("string") => value.is_string(),
+            Some("integer") => value.is_i64() || value.is_u64(),
+            Some("number") => value.is_number(),
+            Some("boolean") => value.is_boolean(),
+            _ => true,
+        };
+        if is_valid {
+            Ok(())
+        } else {
+            Err(format!("{} is {}, not {}", at, value, schema["type"]))
+        }
+    }
+
+    /// Checks that the endpoint at the documented path has a response with
+    /// status and content type, and that JSON bodies match its schema
+    pub fn check_response(method: &str, path: &str, status: u16, content_type: &str, body: &[u8]) {
+        let document = document(&Config::default());
+        let endpoint = format!("{} {} with status {}", method, path, status);
+        let response = &document["paths"][path][method]["responses"][status.to_string()];
+        assert!(response.is_object(), "{} is not documented", endpoint);
+
+        let content = match response["content"].as_object() {
+            Some(content) => content,
+            None => return assert!(body.is_empty(), "{} has no body", endpoint),
+        };
+        let media_type = content_type.split(';').next().unwrap_or_default().trim();
+        let media = content
+            .get(media_type)
+            .or_else(|| content.get("*/*"))
+            .unwrap_or_else(|| panic!("{} is not documented as {}", endpoint, media_type));
+        if media["schema"].is_object() {
+            let value = serde_json::from_slice(body).unwrap();
+            if let Err(err) = check(&document, &media["schema"], &value, "body") {
+                panic!(
+                    "{} does not match the document: {}\n{}",
+                    endpoint, err, value
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn examples_match_their_schemas() {
+        let document = document(&Config::default());
+        for (name, schema) in document["components"]["schemas"].as_object().unwrap() {
+            if !schema["example"].is_null() {
+                check(&document, schema, &schema["example"], name).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn errors_match_their_schemas() {
+        let document = document(&Config::default());
+        let errors = vec![
+            Error::not_found(),
+            Error::too_many_entries(100),
+            Error::domain_not_allowed("example.com").on_field("url"),
+            Error::invalid_request("invalid_json", "Invalid JSON", "EOF"),
+        ];
+        for err in errors {
+            let error = json!({ "$ref": "#/components/schemas/Error" });
+            check(&document, &error, &err.to_json(), "error").unwrap();
+            let problem = json!({ "$ref": "#/components/schemas/Problem" });
+            check(&document, &problem, &err.to_problem(), "problem").unwrap();
+        }
+    }
+
+    #[test]
+    fn rejects_values_that_differ_from_the_schema() {
+        let document = document(&Config::default());
+        let status = json!({ "$ref": "#/components/schemas/Status" });
+        let value = json!({ "status": "ok", "message": "1z5", "url": "https://example.com" });
+        assert!(check(&document, &status, &value, "status").is_err());
+
+        let patch = json!({ "$ref": "#/components/schemas/UrlPatchData" });
+        let value = json!({ "redirect": null, "image": null, "unfurl": "crawlers" });
+        assert!(check(&document, &patch, &value, "patch").is_ok());
+        let value = json!({ "redirect": "303" });
+        assert!(check(&document, &patch, &value, "patch").is_err());
+    }
+
+    #[test]
+    fn documents_path_parameters() {
+        let document = document(&Config::default());
+        let parameters = &document["paths"]["/{short_code}.{format}"]["get"]["parameters"];
+        let names = parameters
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|parameter| parameter["in"] == "path")
+            .map(|parameter| parameter["name"].as_str().unwrap())
+            .collect::<Vec<&str>>();
+        assert_eq!(names, vec!["short_code", "format"]);
+    }
+}
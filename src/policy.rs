@@ -0,0 +1,210 @@
+//! Domain allow and deny lists and allowed URL schemes for link targets.
+//!
+//! The lists are read from the TOML file given as `domain_policy` in the
+//! configuration and can be reloaded at runtime via the admin API:
+//!
+//! ```toml
+//! allow = []                         # empty allows every domain
+//! deny = ["*.example.net"]
+//!
+//! [users.2]                          # additional rules for user 2
+//! allow = ["example.com", "*.example.org"]
+//! schemes = ["zoommtg"]              # in addition to the configured schemes
+//! ```
+//!
+//! `*.example.org` matches example.org itself and all of its subdomains.
+//! Deny lists always win. The allow lists of a user are combined with the
+//! global one, and if the combined list is not empty, only matching domains
+//! are allowed. Schemes are only checked for new targets, existing links
+//! are checked against the domain lists only.
+
+use super::db::{self, DBValue, Link, Pool, Queries};
+use super::response_types::Error;
+use failure::format_err;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use url::Url;
+
+/// A list of allowed and a list of denied domain patterns,
+/// and URL schemes allowed in addition to the configured ones
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct DomainRules {
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+    pub schemes: Vec<String>,
+}
+
+/// Global rules and additional rules per user id
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct DomainPolicy {
+    #[serde(flatten)]
+    pub global: DomainRules,
+    pub users: HashMap<String, DomainRules>,
+    /// the schemes allowed by the configuration
+    #[serde(skip)]
+    pub schemes: Vec<String>,
+}
+
+/// The currently active domain policy, shared between all workers
+pub struct Policy {
+    path: Option<String>,
+    schemes: Vec<String>,
+    current: RwLock<Arc<DomainPolicy>>,
+}
+
+/// Checks if host matches pattern, which is either a domain or
+/// a wildcard like *.example.com that includes example.com itself
+fn matches(pattern: &str, host: &str) -> bool {
+    let pattern = pattern.trim().trim_end_matches('.').to_ascii_lowercase();
+
+    match pattern.strip_prefix("*.") {
+        Some(domain) => {
+            host == domain
+                || (host.ends_with(domain) && host[..host.len() - domain.len()].ends_with('.'))
+        }
+        None => host == pattern,
+    }
+}
+
+impl DomainPolicy {
+    /// Reads the policy from the given TOML file, if any
+    fn from_file(path: Option<&str>, schemes: &[String]) -> Result<DomainPolicy, failure::Error> {
+        let mut policy: DomainPolicy = match path {
+            Some(path) => toml::from_str(&std::fs::read_to_string(path)?)?,
+            None => DomainPolicy::default(),
+        };
+        policy.schemes = schemes.to_vec();
+        Ok(policy)
+    }
+
+    /// Only policies with user rules need to know the user of a request
+    pub fn has_user_rules(&self) -> bool {
+        !self.users.is_empty()
+    }
+
+    /// Checks if user_id may shorten URLs pointing to host
+    pub fn is_allowed(&self, user_id: i64, host: &str) -> bool {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        let user = self.users.get(&user_id.to_string());
+        let rules = std::iter::once(&self.global).chain(user);
+
+        let mut allow = rules.clone().flat_map(|rules| &rules.allow).peekable();
+        let mut deny = rules.flat_map(|rules| &rules.deny);
+
+        if deny.any(|pattern| matches(pattern, &host)) {
+            return false;
+        }
+        allow.peek().is_none() || allow.any(|pattern| matches(pattern, &host))
+    }
+
+    /// Checks if user_id may shorten URLs with scheme
+    pub fn is_scheme_allowed(&self, user_id: i64, scheme: &str) -> bool {
+        let user = self.users.get(&user_id.to_string());
+
+        self.schemes
+            .iter()
+            .chain(&self.global.schemes)
+            .chain(user.into_iter().flat_map(|rules| &rules.schemes))
+            .any(|allowed| allowed.eq_ignore_ascii_case(scheme))
+    }
+
+    /// Fails with a structured error if user_id is not allowed
+    /// to link to url, because of its scheme or domain
+    pub fn check(&self, user_id: i64, url: &Url) -> Result<(), Error> {
+        if !self.is_scheme_allowed(user_id, url.scheme()) {
+            debug!("User {} is not allowed to link to {}", user_id, url);
+            return Err(Error::scheme_not_allowed(url.scheme()));
+        }
+        self.check_domain(user_id, url)
+    }
+
+    /// Fails with a structured error if url points to a domain
+    /// that user_id is not allowed to link to
+    fn check_domain(&self, user_id: i64, url: &Url) -> Result<(), Error> {
+        match url.host_str() {
+            Some(host) if !self.is_allowed(user_id, host) => {
+                debug!("User {} is not allowed to link to {}", user_id, host);
+                Err(Error::domain_not_allowed(host))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks a stored link against the policy
+    fn is_blocked(&self, link: &Link) -> bool {
+        Url::parse(&link.url)
+            .map(|url| self.check_domain(link.user_id, &url).is_err())
+            .unwrap_or(false)
+    }
+}
+
+impl Policy {
+    /// Loads the policy from path, or an empty policy that allows every
+    /// domain, and combines it with the configured schemes
+    pub fn load(path: Option<&str>, schemes: &[String]) -> Result<Policy, failure::Error> {
+        let policy = DomainPolicy::from_file(path, schemes)?;
+
+        Ok(Policy {
+            path: path.map(str::to_owned),
+            schemes: schemes.to_vec(),
+            current: RwLock::new(Arc::new(policy)),
+        })
+    }
+
+    /// Reads the policy file again, keeps the current policy on errors
+    pub fn reload(&self) -> Result<(), failure::Error> {
+        let policy = DomainPolicy::from_file(self.path.as_deref(), &self.schemes)?;
+        *self.current.write().unwrap() = Arc::new(policy);
+        Ok(())
+    }
+
+    /// Returns the currently active policy
+    pub fn current(&self) -> Arc<DomainPolicy> {
+        self.current.read().unwrap().clone()
+    }
+}
+
+/// Returns the id of the user assigned to api_key
+pub async fn user_id(pool: &Pool, api_key: &str) -> Result<i64, Error> {
+    match db::query(pool, Queries::GetUserId(api_key.to_owned())).await? {
+        DBValue::Number(user_id) => Ok(user_id),
+        value => {
+            debug!(
+                "Got unexpected type back from GetUserId query: {:#?}",
+                value
+            );
+            Err(Error::internal())
+        }
+    }
+}
+
+/// Checks all stored links against the policy, blocks links to domains that
+/// are not allowed (anymore) and unblocks the rest.
+/// Returns the number of newly blocked and unblocked links.
+pub async fn recheck_links(
+    pool: &Pool,
+    policy: &DomainPolicy,
+) -> Result<(usize, usize), failure::Error> {
+    let links = match db::query(pool, Queries::AllLinks).await? {
+        DBValue::Links(links, _) => links,
+        value => return Err(format_err!("Unexpected value {:?}", value)),
+    };
+
+    let changes = links
+        .iter()
+        .map(|link| (link, policy.is_blocked(link)))
+        .filter(|(link, blocked)| link.blocked_at.is_some() != *blocked)
+        .map(|(link, blocked)| (link.code.clone(), blocked))
+        .collect::<Vec<(String, bool)>>();
+
+    let blocked = changes.iter().filter(|(_, blocked)| *blocked).count();
+    let unblocked = changes.len() - blocked;
+
+    if !changes.is_empty() {
+        db::query(pool, Queries::SetBlocked(changes)).await?;
+    }
+    Ok((blocked, unblocked))
+}
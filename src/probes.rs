@@ -0,0 +1,147 @@
+//! Liveness and readiness probes for orchestrators like Kubernetes.
+//!
+//! `/healthz` succeeds as long as the process handles requests. `/readyz`
+//! checks that the connection pool hands out a connection, that the database
+//! schema is up to date and that the disk of the database is writable. With
+//! `shutdown_delay` set, it also fails for that long after SIGTERM or SIGINT,
+//! before the service shuts down gracefully, so that load balancers stop
+//! sending requests while they are still answered.
+
+use super::config::ProbesConfig;
+use super::db::{self, Pool, Queries};
+use super::metrics;
+use super::response_types::{ProbeCheck, Readiness};
+use actix_server::Server;
+use actix_web::error::BlockingError;
+use actix_web::rt::time::{delay_for, timeout};
+use futures::future::{self, Future};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Paths of the probes, which are neither rate limited nor logged
+pub const PATHS: &[&str] = &["/healthz", "/readyz"];
+
+/// State of the probes, shared between all workers
+pub struct Probes {
+    timeout: Duration,
+    shutdown_delay: u64,
+    shutting_down: AtomicBool,
+}
+
+/// Runs a check, failing it if it takes longer than wait
+async fn run<F>(wait: Duration, check: F) -> ProbeCheck
+where
+    F: Future<Output = Result<(), String>>,
+{
+    let result = match timeout(wait, check).await {
+        Ok(result) => result,
+        Err(_) => Err(String::from("Timed out")),
+    };
+    if let Err(err) = &result {
+        warn!("Readiness check failed: {}", err);
+    }
+    ProbeCheck::new(result)
+}
+
+/// Checks that the pool hands out a connection within wait
+async fn check_pool(pool: Pool, wait: Duration) -> Result<(), String> {
+    match metrics::block(move || pool.get_timeout(wait).map(|_| ())).await {
+        Ok(()) => Ok(()),
+        Err(BlockingError::Error(err)) => Err(err.to_string()),
+        Err(BlockingError::Canceled) => Err(String::from("Canceled")),
+    }
+}
+
+/// Runs a database query that returns nothing on success
+async fn check_query(pool: &Pool, query: Queries) -> Result<(), String> {
+    db::query(pool, query)
+        .await
+        .map(|_| ())
+        .map_err(|err| err.to_string())
+}
+
+impl Probes {
+    pub fn new(config: &ProbesConfig) -> Probes {
+        Probes {
+            timeout: Duration::from_secs(config.timeout),
+            shutdown_delay: config.shutdown_delay,
+            shutting_down: AtomicBool::new(false),
+        }
+    }
+
+    /// Checks if the probes delay the shutdown, see shutdown_on_signal
+    pub fn delays_shutdown(&self) -> bool {
+        self.shutdown_delay > 0
+    }
+
+    /// Runs all readiness checks at once
+    pub async fn readiness(&self, pool: &Pool) -> Readiness {
+        let (connection, schema, disk) = future::join3(
+            run(self.timeout, check_pool(pool.clone(), self.timeout)),
+            run(self.timeout, check_query(pool, Queries::NeedsInit)),
+            run(self.timeout, check_query(pool, Queries::CheckWritable)),
+        )
+        .await;
+        let shutdown = if self.shutting_down.load(Ordering::Relaxed) {
+            Err(String::from("Shutting down"))
+        } else {
+            Ok(())
+        };
+
+        let mut checks = BTreeMap::new();
+        checks.insert("connection", connection);
+        checks.insert("schema", schema);
+        checks.insert("disk", disk);
+        checks.insert("shutdown", ProbeCheck::new(shutdown));
+        let is_ready = checks.values().all(ProbeCheck::is_ok);
+
+        Readiness {
+            status: if is_ready { "ready" } else { "not_ready" },
+            checks,
+        }
+    }
+}
+
+/// Resolves on SIGTERM or SIGINT
+#[cfg(unix)]
+async fn terminated() {
+    use actix_web::rt::signal::{ctrl_c, unix};
+
+    match unix::signal(unix::SignalKind::terminate()) {
+        Ok(mut terminate) => {
+            let terminate = Box::pin(async move { terminate.recv().await });
+            future::select(terminate, Box::pin(ctrl_c())).await;
+        }
+        Err(err) => {
+            warn!("Could not listen for SIGTERM: {}", err);
+            let _ = ctrl_c().await;
+        }
+    }
+}
+
+/// Resolves on Ctrl-C, there is no SIGTERM on other platforms
+#[cfg(not(unix))]
+async fn terminated() {
+    if let Err(err) = actix_web::rt::signal::ctrl_c().await {
+        warn!("Could not listen for Ctrl-C: {}", err);
+        future::pending::<()>().await;
+    }
+}
+
+/// Fails the readiness probe on SIGTERM or SIGINT and stops server
+/// gracefully after the shutdown delay. The server has to be started with
+/// its own signal handling disabled.
+pub fn shutdown_on_signal(server: Server, probes: Arc<Probes>) {
+    actix_web::rt::spawn(async move {
+        terminated().await;
+        info!(
+            "Shutting down in {} seconds, failing readiness until then",
+            probes.shutdown_delay
+        );
+        probes.shutting_down.store(true, Ordering::Relaxed);
+        delay_for(Duration::from_secs(probes.shutdown_delay)).await;
+        server.stop(true).await;
+    });
+}
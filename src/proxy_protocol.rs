@@ -0,0 +1,238 @@
+//! The PROXY protocol of HAProxy, in version 1 (text) and 2 (binary).
+//!
+//! Load balancers that forward TCP connections send a header with the address
+//! of the client before anything else, so that it is known without parsing
+//! HTTP. With the protocol enabled, every connection has to start with such a
+//! header and the address from it is used as peer address of its requests.
+//! See <https://www.haproxy.org/download/2.4/doc/proxy-protocol.txt>
+
+use actix_codec::AsyncRead;
+use actix_web::rt::{net::TcpStream, time::timeout};
+use futures::future::poll_fn;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::time::Duration;
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// Maximum length of a version 1 header, including the CRLF
+const V1_MAX_LENGTH: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Signature, version and command, address family, address length
+const V2_HEADER_LENGTH: usize = 16;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Fills buf from stream, without reading any further
+async fn read_exact(stream: &mut TcpStream, buf: &mut [u8]) -> io::Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let read = poll_fn(|cx| Pin::new(&mut *stream).poll_read(cx, &mut buf[filled..])).await?;
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        filled += read;
+    }
+    Ok(())
+}
+
+/// Parses a version 1 header like `PROXY TCP4 1.2.3.4 5.6.7.8 5678 80\r\n`.
+/// Returns None for `PROXY UNKNOWN`, which load balancers send for their
+/// own connections like health checks.
+fn parse_v1(header: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let header = std::str::from_utf8(header)
+        .ok()
+        .and_then(|header| header.strip_suffix("\r\n"))
+        .ok_or_else(|| invalid("Invalid PROXY header"))?;
+    let fields = header.split(' ').collect::<Vec<&str>>();
+
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4", source, _, port, _] | ["PROXY", "TCP6", source, _, port, _] => {
+            let ip = source.parse::<IpAddr>();
+            let port = port.parse::<u16>();
+            match (ip, port) {
+                (Ok(ip), Ok(port)) => Ok(Some(SocketAddr::new(ip, port))),
+                _ => Err(invalid("Invalid address in PROXY header")),
+            }
+        }
+        _ => Err(invalid("Invalid PROXY header")),
+    }
+}
+
+/// Parses the addresses of a version 2 header, after its first 16 bytes.
+/// Returns None for LOCAL connections and address families other than TCP
+/// or UDP over IPv4 and IPv6.
+fn parse_v2(command: u8, family: u8, addresses: &[u8]) -> io::Result<Option<SocketAddr>> {
+    match command {
+        0x20 => return Ok(None),
+        0x21 => {}
+        _ => return Err(invalid("Unsupported PROXY protocol version or command")),
+    }
+
+    let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+    match family {
+        0x11 | 0x12 if addresses.len() >= 12 => {
+            let mut ip = [0; 4];
+            ip.copy_from_slice(&addresses[..4]);
+            Ok(Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port(8))))
+        }
+        0x21 | 0x22 if addresses.len() >= 36 => {
+            let mut ip = [0; 16];
+            ip.copy_from_slice(&addresses[..16]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port(32))))
+        }
+        0x11 | 0x12 | 0x21 | 0x22 => Err(invalid("Truncated address in PROXY header")),
+        _ => Ok(None),
+    }
+}
+
+/// Reads the PROXY header from the start of stream and returns the address
+/// of the client, or None if the load balancer doesn't know it. The header is
+/// read byte-exact, so that the HTTP request after it stays in the stream.
+async fn read_header(stream: &mut TcpStream) -> io::Result<Option<SocketAddr>> {
+    let mut header = vec![0; V1_PREFIX.len()];
+    read_exact(stream, &mut header).await?;
+
+    if header == V1_PREFIX {
+        let mut byte = [0];
+        while !header.ends_with(b"\r\n") {
+            if header.len() >= V1_MAX_LENGTH {
+                return Err(invalid("PROXY header too long"));
+            }
+            read_exact(stream, &mut byte).await?;
+            header.push(byte[0]);
+        }
+        return parse_v1(&header);
+    }
+
+    header.resize(V2_HEADER_LENGTH, 0);
+    read_exact(stream, &mut header[V1_PREFIX.len()..]).await?;
+    if !header.starts_with(V2_SIGNATURE) {
+        return Err(invalid("Missing PROXY header"));
+    }
+    let length = u16::from_be_bytes([header[14], header[15]]) as usize;
+    let mut addresses = vec![0; length];
+    read_exact(stream, &mut addresses).await?;
+    parse_v2(header[12], header[13], &addresses)
+}
+
+/// Reads the PROXY header of a new connection within wait seconds and returns
+/// the connection with the address of the client as its peer address
+pub async fn accept(
+    mut stream: TcpStream,
+    wait: u64,
+) -> io::Result<(TcpStream, Option<SocketAddr>)> {
+    let client = match timeout(Duration::from_secs(wait), read_header(&mut stream)).await {
+        Ok(client) => client?,
+        Err(_) => return Err(io::ErrorKind::TimedOut.into()),
+    };
+    let peer = match client {
+        Some(client) => Some(client),
+        None => stream.peer_addr().ok(),
+    };
+    Ok((stream, peer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    /// Builds a version 2 header with command, family and addresses
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[command, family]);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    /// Returns both sides of a local connection whose client has sent data
+    fn connection(data: &[u8]) -> (TcpStream, std::net::TcpStream) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.write_all(data).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        (TcpStream::from_std(stream).unwrap(), client)
+    }
+
+    #[test]
+    fn parses_v1_headers() {
+        let addr = parse_v1(b"PROXY TCP4 1.2.3.4 5.6.7.8 5678 80\r\n").unwrap();
+        assert_eq!(addr, Some("1.2.3.4:5678".parse().unwrap()));
+        let addr = parse_v1(b"PROXY TCP6 2001:db8::1 2001:db8::2 5678 443\r\n").unwrap();
+        assert_eq!(addr, Some("[2001:db8::1]:5678".parse().unwrap()));
+        assert_eq!(parse_v1(b"PROXY UNKNOWN\r\n").unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_invalid_v1_headers() {
+        assert!(parse_v1(b"PROXY TCP4 1.2.3.4 5.6.7.8 5678 80").is_err());
+        assert!(parse_v1(b"PROXY TCP4 1.2.3.4 5.6.7.8 99999 80\r\n").is_err());
+        assert!(parse_v1(b"PROXY TCP4 example.com 5.6.7.8 5678 80\r\n").is_err());
+        assert!(parse_v1(b"PROXY UDP4 1.2.3.4 5.6.7.8 5678 80\r\n").is_err());
+    }
+
+    #[test]
+    fn parses_v2_addresses() {
+        let ipv4 = [1, 2, 3, 4, 5, 6, 7, 8, 0x16, 0x2e, 0, 80];
+        let addr = parse_v2(0x21, 0x11, &ipv4).unwrap();
+        assert_eq!(addr, Some("1.2.3.4:5678".parse().unwrap()));
+
+        let mut ipv6 = vec![0; 36];
+        ipv6[0] = 0x20;
+        ipv6[1] = 0x01;
+        ipv6[15] = 1;
+        ipv6[32..34].copy_from_slice(&5678u16.to_be_bytes());
+        let addr = parse_v2(0x21, 0x21, &ipv6).unwrap();
+        assert_eq!(addr, Some("[2001::1]:5678".parse().unwrap()));
+
+        // LOCAL connections and unix sockets have no client address
+        assert_eq!(parse_v2(0x20, 0x11, &ipv4).unwrap(), None);
+        assert_eq!(parse_v2(0x21, 0x31, &[0; 216]).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_invalid_v2_headers() {
+        assert!(parse_v2(0x11, 0x11, &[0; 12]).is_err());
+        assert!(parse_v2(0x21, 0x11, &[0; 11]).is_err());
+        assert!(parse_v2(0x21, 0x21, &[0; 12]).is_err());
+    }
+
+    #[actix_rt::test]
+    async fn reads_only_the_header() {
+        let request = b"GET / HTTP/1.1\r\n\r\n";
+        let mut v1 = b"PROXY TCP4 1.2.3.4 5.6.7.8 5678 80\r\n".to_vec();
+        let mut v2 = v2(0x21, 0x11, &[1, 2, 3, 4, 5, 6, 7, 8, 0x16, 0x2e, 0, 80]);
+
+        for data in [&mut v1, &mut v2].iter_mut() {
+            data.extend_from_slice(request);
+            let (mut stream, _client) = connection(data);
+            let client = read_header(&mut stream).await.unwrap();
+            assert_eq!(client, Some("1.2.3.4:5678".parse().unwrap()));
+
+            let mut rest = vec![0; request.len()];
+            read_exact(&mut stream, &mut rest).await.unwrap();
+            assert_eq!(rest, request);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn rejects_connections_without_header() {
+        let (mut stream, _client) = connection(b"GET / HTTP/1.1\r\n\r\n");
+        assert!(read_header(&mut stream).await.is_err());
+
+        let mut long = V1_PREFIX.to_vec();
+        long.extend_from_slice(&[b'x'; 200]);
+        let (mut stream, _client) = connection(&long);
+        assert!(read_header(&mut stream).await.is_err());
+
+        let (stream, _client) = connection(b"PROXY TCP4");
+        let err = accept(stream, 1).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+}
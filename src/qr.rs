@@ -0,0 +1,254 @@
+use qrcode::{Color, EcLevel, QrCode};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Upper bounds to keep rendering cheap
+const MAX_SIZE: u32 = 2048;
+const MAX_MARGIN: u32 = 16;
+
+/// Query parameters to customize QR codes, for example
+/// `?size=512&margin=2&ec=H&fg=345&bg=ffffff`
+#[derive(Default, Serialize, Deserialize, JsonSchema)]
+pub struct QrQuery {
+    /// minimum width and height in pixels
+    pub size: Option<u32>,
+    /// quiet zone around the code in modules
+    pub margin: Option<u32>,
+    /// error correction level, one of L, M, Q or H
+    pub ec: Option<String>,
+    /// foreground and background colour as hex value, like fff or 334455
+    pub fg: Option<String>,
+    pub bg: Option<String>,
+    /// svg or png, only needed if the path has no file extension
+    pub format: Option<String>,
+}
+
+/// Validated rendering options, built from QrQuery
+pub struct QrOptions {
+    size: u32,
+    margin: u32,
+    ec: EcLevel,
+    fg: [u8; 3],
+    bg: [u8; 3],
+}
+
+/// A QR code as matrix of dark (true) and light (false) modules,
+/// including the margin
+struct Matrix {
+    width: usize,
+    modules: Vec<bool>,
+    scale: u32,
+}
+
+/// Parses a hex colour like "345" or "#334455"
+fn parse_color(color: &str) -> Result<[u8; 3], &'static str> {
+    let hex = color.strip_prefix('#').unwrap_or(color);
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err("Invalid colour");
+    }
+    let channel = |s: &str| u8::from_str_radix(s, 16).map_err(|_| "Invalid colour");
+
+    match hex.len() {
+        3 => {
+            let mut rgb = [0; 3];
+            for (i, c) in hex.char_indices() {
+                rgb[i] = channel(&c.to_string())? * 17;
+            }
+            Ok(rgb)
+        }
+        6 => Ok([
+            channel(&hex[0..2])?,
+            channel(&hex[2..4])?,
+            channel(&hex[4..6])?,
+        ]),
+        _ => Err("Invalid colour"),
+    }
+}
+
+impl QrOptions {
+    /// Validates the query and fills in defaults
+    pub fn from_query(query: &QrQuery) -> Result<QrOptions, &'static str> {
+        let size = query.size.unwrap_or(256);
+        let margin = query.margin.unwrap_or(4);
+
+        if size > MAX_SIZE {
+            return Err("QR code size too big");
+        }
+        if margin > MAX_MARGIN {
+            return Err("QR code margin too big");
+        }
+
+        let ec = match query.ec.as_deref().unwrap_or("M") {
+            "L" | "l" => EcLevel::L,
+            "M" | "m" => EcLevel::M,
+            "Q" | "q" => EcLevel::Q,
+            "H" | "h" => EcLevel::H,
+            _ => return Err("Invalid error correction level"),
+        };
+
+        Ok(QrOptions {
+            size,
+            margin,
+            ec,
+            fg: parse_color(query.fg.as_deref().unwrap_or("000"))?,
+            bg: parse_color(query.bg.as_deref().unwrap_or("fff"))?,
+        })
+    }
+
+    /// Encodes data and adds the margin
+    fn matrix(&self, data: &str) -> Result<Matrix, &'static str> {
+        let code = QrCode::with_error_correction_level(data, self.ec)
+            .map_err(|_| "Data too long for a QR code")?;
+        let code_width = code.width();
+        let colors = code.to_colors();
+        let margin = self.margin as usize;
+        let width = code_width + 2 * margin;
+
+        let mut modules = vec![false; width * width];
+        for (i, color) in colors.into_iter().enumerate() {
+            let (x, y) = (i % code_width + margin, i / code_width + margin);
+            modules[y * width + x] = color == Color::Dark;
+        }
+
+        let scale = (self.size as f64 / width as f64).ceil().max(1.0) as u32;
+        Ok(Matrix {
+            width,
+            modules,
+            scale,
+        })
+    }
+}
+
+/// Renders data as QR code in SVG format
+pub fn svg(data: &str, options: &QrOptions) -> Result<String, &'static str> {
+    let matrix = options.matrix(data)?;
+    let pixels = matrix.width as u32 * matrix.scale;
+    let color = |c: [u8; 3]| format!("#{:02x}{:02x}{:02x}", c[0], c[1], c[2]);
+
+    let mut path = String::new();
+    for (i, _) in matrix.modules.iter().enumerate().filter(|(_, &dark)| dark) {
+        let (x, y) = (i % matrix.width, i / matrix.width);
+        path.push_str(&format!("M{},{}h1v1h-1z", x, y));
+    }
+
+    Ok(format!(
+        concat!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" version="1.1" "#,
+            r#"width="{px}" height="{px}" viewBox="0 0 {w} {w}" shape-rendering="crispEdges">"#,
+            r#"<rect width="{w}" height="{w}" fill="{bg}"/>"#,
+            r#"<path d="{path}" fill="{fg}"/>"#,
+            "</svg>"
+        ),
+        px = pixels,
+        w = matrix.width,
+        bg = color(options.bg),
+        fg = color(options.fg),
+        path = path,
+    ))
+}
+
+/// Renders data as QR code in PNG format
+pub fn png(data: &str, options: &QrOptions) -> Result<Vec<u8>, &'static str> {
+    let matrix = options.matrix(data)?;
+    let scale = matrix.scale as usize;
+    let pixels = matrix.width * scale;
+
+    let mut image = Vec::with_capacity(pixels * pixels * 3);
+    for y in 0..pixels {
+        for x in 0..pixels {
+            let dark = matrix.modules[(y / scale) * matrix.width + x / scale];
+            image.extend_from_slice(if dark { &options.fg } else { &options.bg });
+        }
+    }
+
+    let mut buffer = Vec::new();
+    let mut encoder = png::Encoder::new(&mut buffer, pixels as u32, pixels as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder
+        .write_header()
+        .and_then(|mut writer| writer.write_image_data(&image))
+        .map_err(|_| "Failed to encode PNG")?;
+
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(query: QrQuery) -> QrOptions {
+        QrOptions::from_query(&query).unwrap()
+    }
+
+    #[test]
+    fn parses_colors() {
+        assert_eq!(parse_color("345"), Ok([0x33, 0x44, 0x55]));
+        assert_eq!(parse_color("#fFf"), Ok([255, 255, 255]));
+        assert_eq!(parse_color("#0a1B2c"), Ok([0x0a, 0x1b, 0x2c]));
+        for color in &[
+            "", "#", "12", "1234", "1234567", "##345", "ggg", "+f+f+f", "+ff", "€€",
+        ] {
+            assert_eq!(parse_color(color), Err("Invalid colour"), "{}", color);
+        }
+    }
+
+    #[test]
+    fn validates_queries() {
+        let query = |size, margin, ec: &str| QrQuery {
+            size: Some(size),
+            margin: Some(margin),
+            ec: Some(ec.to_owned()),
+            ..QrQuery::default()
+        };
+        assert!(QrOptions::from_query(&query(MAX_SIZE, MAX_MARGIN, "h")).is_ok());
+        assert!(QrOptions::from_query(&query(MAX_SIZE + 1, 4, "M")).is_err());
+        assert!(QrOptions::from_query(&query(256, MAX_MARGIN + 1, "M")).is_err());
+        assert!(QrOptions::from_query(&query(256, 4, "X")).is_err());
+        assert!(QrOptions::from_query(&QrQuery {
+            fg: Some("red".to_owned()),
+            ..QrQuery::default()
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn renders_svg() {
+        let svg = svg(
+            "https://example.com/k0r",
+            &options(QrQuery {
+                size: Some(0),
+                margin: Some(0),
+                fg: Some("345".to_owned()),
+                ..QrQuery::default()
+            }),
+        )
+        .unwrap();
+        // version 2 has 25 modules, scaled up to at least one pixel each
+        assert!(svg.contains(r#"width="25" height="25" viewBox="0 0 25 25""#));
+        assert!(svg.contains(r##"fill="#334455""##));
+        assert!(svg.contains(r##"fill="#ffffff""##));
+        assert!(svg.contains("M0,0h1v1h-1z"));
+    }
+
+    #[test]
+    fn renders_png() {
+        let data = png("k0r", &options(QrQuery::default())).unwrap();
+        let decoder = png::Decoder::new(&data[..]);
+        let mut reader = decoder.read_info().unwrap();
+        let mut image = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut image).unwrap();
+        // version 1 has 21 modules plus a margin of 4 on both sides
+        assert_eq!((info.width, info.height), (29 * 9, 29 * 9));
+        // the margin is light, the top left finder pattern dark
+        assert_eq!(image[..3], [255, 255, 255]);
+        let dark = (4 * 9 * info.width as usize + 4 * 9) * 3;
+        assert_eq!(image[dark..dark + 3], [0, 0, 0]);
+    }
+
+    #[test]
+    fn rejects_too_much_data() {
+        let data = "x".repeat(8000);
+        assert!(svg(&data, &options(QrQuery::default())).is_err());
+    }
+}
@@ -0,0 +1,354 @@
+//! Rate limiting of short link lookups per client address.
+//!
+//! Short codes are sequential, so scrapers can walk the whole code space.
+//! Besides limiting the number of lookups per time window, clients that get
+//! many not found responses in a row or look up consecutive short codes are
+//! banned for a while. Limited and banned clients get `429 Too Many Requests`.
+
+use super::client;
+use super::config::RateLimitConfig;
+use super::probes;
+use super::response_types::{Error, RateLimitStats};
+use super::short_code::ShortCode;
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderValue, RETRY_AFTER},
+    http::{Method, StatusCode},
+    rt::time::delay_for,
+    HttpRequest, HttpResponse,
+};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// What is known about a single client address
+struct Client {
+    window_start: Instant,
+    requests: u32,
+    /// not found responses in a row
+    not_found: u32,
+    last_code: Option<usize>,
+    /// lookups of consecutive short codes in a row
+    sequential: u32,
+    banned_until: Option<Instant>,
+}
+
+impl Client {
+    fn new(now: Instant) -> Client {
+        Client {
+            window_start: now,
+            requests: 0,
+            not_found: 0,
+            last_code: None,
+            sequential: 0,
+            banned_until: None,
+        }
+    }
+
+    fn is_banned(&self, now: Instant) -> bool {
+        self.banned_until.is_some_and(|until| until > now)
+    }
+}
+
+/// Rate limits lookups per client address, shared between all workers
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    /// paths of other routes that are no lookups, like the metrics
+    ignored: Vec<String>,
+    clients: Mutex<HashMap<IpAddr, Client>>,
+    /// requests rejected because of the rate limit
+    limited: AtomicU64,
+    /// clients banned because of not found responses or consecutive codes
+    banned: AtomicU64,
+    /// requests rejected because the client is banned
+    rejected: AtomicU64,
+}
+
+/// Marks a lookup of a named link, whose path is no short code
+struct Named;
+
+/// Marks req as lookup of a named link, so that its path doesn't count
+/// as short code when looking for consecutive lookups
+pub fn mark_named(req: &HttpRequest) {
+    req.extensions_mut().insert(Named);
+}
+
+/// Reads the short code from paths like `/1z5`, `/1z5+` or `/1z5.svg`
+fn short_code(path: &str) -> Option<usize> {
+    let code = path
+        .trim_start_matches('/')
+        .split(&['/', '+', '.'][..])
+        .next()?;
+    ShortCode::from_code(code).ok().map(|code| code.n)
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig, ignored: Vec<String>) -> RateLimiter {
+        RateLimiter {
+            config: config.clone(),
+            ignored,
+            clients: Mutex::new(HashMap::new()),
+            limited: AtomicU64::new(0),
+            banned: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    /// Only short link lookups are limited, not the index page, static
+    /// files, the favicon browsers ask for, probes, ignored paths or the API
+    fn is_lookup(&self, req: &ServiceRequest) -> bool {
+        let path = req.path();
+        req.method() == Method::GET
+            && path != "/"
+            && path != "/favicon.ico"
+            && !path.starts_with("/static/")
+            && !path.starts_with("/api/")
+            && !probes::PATHS.contains(&path)
+            && !self.ignored.iter().any(|ignored| ignored == path)
+    }
+
+    /// Counts a lookup of client and returns the seconds it has to wait,
+    /// if it is limited or banned
+    pub fn check(&self, client: IpAddr) -> Option<u64> {
+        let now = Instant::now();
+        let window = Duration::from_secs(self.config.window);
+        let mut clients = self.clients.lock().unwrap();
+        let entry = clients.entry(client).or_insert_with(|| Client::new(now));
+
+        if let Some(until) = entry.banned_until.filter(|until| *until > now) {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return Some((until - now).as_secs().max(1));
+        }
+        if now - entry.window_start >= window {
+            entry.window_start = now;
+            entry.requests = 0;
+        }
+        entry.requests += 1;
+
+        if entry.requests > self.config.requests {
+            self.limited.fetch_add(1, Ordering::Relaxed);
+            let remaining = window.checked_sub(now - entry.window_start);
+            return Some(remaining.unwrap_or_default().as_secs().max(1));
+        }
+        None
+    }
+
+    /// Records the response status of a lookup of code, if any, and bans
+    /// the client if it looks like it is enumerating short codes
+    pub fn record(&self, client: IpAddr, code: Option<usize>, status: StatusCode) {
+        let mut clients = self.clients.lock().unwrap();
+        let entry = match clients.get_mut(&client) {
+            Some(entry) => entry,
+            None => return,
+        };
+
+        entry.not_found = match status {
+            StatusCode::NOT_FOUND => entry.not_found + 1,
+            _ => 0,
+        };
+        // lookups of names neither continue nor break a sequence
+        if let Some(code) = code {
+            let is_sequential = matches!(entry.last_code, Some(last) if last.abs_diff(code) == 1);
+            entry.sequential = if is_sequential {
+                entry.sequential + 1
+            } else {
+                0
+            };
+            entry.last_code = Some(code);
+        }
+
+        let exceeds = |count: u32, max: u32| max > 0 && count >= max;
+        if exceeds(entry.not_found, self.config.max_not_found)
+            || exceeds(entry.sequential, self.config.max_sequential)
+        {
+            info!("Banning {} for enumerating short codes", client);
+            entry.banned_until =
+                Some(Instant::now() + Duration::from_secs(self.config.ban_duration));
+            entry.not_found = 0;
+            entry.sequential = 0;
+            self.banned.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Forgets clients that are neither banned nor in their current window
+    pub fn prune(&self) {
+        let now = Instant::now();
+        let window = Duration::from_secs(self.config.window);
+        self.clients
+            .lock()
+            .unwrap()
+            .retain(|_, client| client.is_banned(now) || now - client.window_start < window);
+    }
+
+    /// Returns the current counters
+    pub fn stats(&self) -> RateLimitStats {
+        let now = Instant::now();
+        let clients = self.clients.lock().unwrap();
+
+        RateLimitStats {
+            tracked_clients: clients.len(),
+            banned_clients: clients.values().filter(|c| c.is_banned(now)).count(),
+            limited: self.limited.load(Ordering::Relaxed),
+            banned: self.banned.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Returns the short code that was looked up for response, none for
+/// named links and paths that are no short code
+pub fn looked_up_code(response: &ServiceResponse) -> Option<usize> {
+    let req = response.request();
+    if req.extensions().get::<Named>().is_some() {
+        return None;
+    }
+    short_code(req.path())
+}
+
+/// Builds the response for limited and banned clients
+pub fn too_many_requests(retry_after: u64) -> HttpResponse {
+    let mut response = HttpResponse::from_error(Error::too_many_requests().into());
+    response.headers_mut().insert(
+        RETRY_AFTER,
+        HeaderValue::from_str(&retry_after.to_string()).unwrap(),
+    );
+    response
+}
+
+/// Checks a request against the rate limiter. Responds right away to
+/// limited and banned clients, otherwise returns the request and, for
+/// lookups, the client address to record the response for.
+pub fn limit(
+    limiter: &RateLimiter,
+    req: ServiceRequest,
+) -> Result<(ServiceRequest, Option<IpAddr>), ServiceResponse> {
+    let client = match client::address(req.head()) {
+        Some(ip) if limiter.config.enabled && limiter.is_lookup(&req) => ip,
+        _ => return Ok((req, None)),
+    };
+    match limiter.check(client) {
+        Some(retry_after) => Err(req.into_response(too_many_requests(retry_after))),
+        None => Ok((req, Some(client))),
+    }
+}
+
+/// Regularly forgets clients that are not limited anymore, see prune
+pub fn start(limiter: Arc<RateLimiter>) {
+    if !limiter.config.enabled {
+        return;
+    }
+    let interval = Duration::from_secs(limiter.config.window.max(1));
+
+    actix_web::rt::spawn(async move {
+        loop {
+            delay_for(interval).await;
+            limiter.prune();
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_limiter(requests: u32, max_not_found: u32, max_sequential: u32) -> RateLimiter {
+        let config = RateLimitConfig {
+            enabled: true,
+            requests,
+            window: 60,
+            max_not_found,
+            max_sequential,
+            ban_duration: 60,
+        };
+        RateLimiter::new(&config, Vec::new())
+    }
+
+    fn client() -> IpAddr {
+        IpAddr::from([192, 0, 2, 1])
+    }
+
+    /// Looks up code and records the response status
+    fn lookup(limiter: &RateLimiter, code: Option<usize>, status: StatusCode) -> Option<u64> {
+        let limited = limiter.check(client());
+        limiter.record(client(), code, status);
+        limited
+    }
+
+    #[test]
+    fn reads_short_codes_from_paths() {
+        assert_eq!(short_code("/1z5"), Some(2561));
+        assert_eq!(short_code("/1z5+"), Some(2561));
+        assert_eq!(short_code("/1z5.svg"), Some(2561));
+        assert_eq!(short_code("/1z5/any/path"), Some(2561));
+        assert_eq!(short_code("/no-code"), None);
+        assert_eq!(short_code("/"), None);
+    }
+
+    #[test]
+    fn limits_requests_per_window() {
+        let limiter = new_limiter(2, 0, 0);
+        assert_eq!(lookup(&limiter, Some(1), StatusCode::FOUND), None);
+        assert_eq!(lookup(&limiter, Some(1), StatusCode::FOUND), None);
+        assert!(lookup(&limiter, Some(1), StatusCode::FOUND).is_some());
+        assert_eq!(limiter.stats().limited, 1);
+    }
+
+    #[test]
+    fn bans_clients_with_many_not_found() {
+        let limiter = new_limiter(100, 3, 0);
+        lookup(&limiter, Some(10), StatusCode::NOT_FOUND);
+        lookup(&limiter, Some(20), StatusCode::FOUND);
+        lookup(&limiter, Some(30), StatusCode::NOT_FOUND);
+        lookup(&limiter, Some(40), StatusCode::NOT_FOUND);
+        assert_eq!(limiter.stats().banned, 0);
+
+        lookup(&limiter, Some(50), StatusCode::NOT_FOUND);
+        assert_eq!(limiter.stats().banned, 1);
+        assert!(limiter.check(client()).is_some());
+        assert_eq!(limiter.stats().rejected, 1);
+    }
+
+    #[test]
+    fn bans_clients_walking_consecutive_codes() {
+        let limiter = new_limiter(100, 0, 3);
+        for code in 100..103 {
+            lookup(&limiter, Some(code), StatusCode::FOUND);
+        }
+        assert_eq!(limiter.stats().banned, 0);
+        lookup(&limiter, Some(103), StatusCode::FOUND);
+        assert_eq!(limiter.stats().banned, 1);
+
+        let limiter = new_limiter(100, 0, 3);
+        for code in (100..104).rev() {
+            lookup(&limiter, Some(code), StatusCode::FOUND);
+        }
+        assert_eq!(limiter.stats().banned, 1);
+    }
+
+    #[test]
+    fn named_lookups_do_not_break_sequences() {
+        let limiter = new_limiter(100, 0, 3);
+        for code in &[Some(100), Some(101), None, Some(102)] {
+            lookup(&limiter, *code, StatusCode::FOUND);
+        }
+        assert_eq!(limiter.stats().banned, 0);
+        lookup(&limiter, None, StatusCode::FOUND);
+        lookup(&limiter, Some(103), StatusCode::FOUND);
+        assert_eq!(limiter.stats().banned, 1);
+    }
+
+    #[test]
+    fn handles_the_largest_short_code() {
+        let largest = short_code("/3w5e11264sgsf");
+        assert_eq!(largest, Some(usize::MAX));
+
+        let limiter = new_limiter(100, 0, 2);
+        lookup(&limiter, largest, StatusCode::NOT_FOUND);
+        lookup(&limiter, largest, StatusCode::NOT_FOUND);
+        lookup(&limiter, Some(usize::MAX - 1), StatusCode::NOT_FOUND);
+        lookup(&limiter, largest, StatusCode::NOT_FOUND);
+        assert_eq!(limiter.stats().banned, 1);
+    }
+}
@@ -0,0 +1,215 @@
+use super::config::Config;
+use super::db::Link;
+use super::render;
+use super::templates;
+use actix_web::{
+    http::header::{CACHE_CONTROL, LOCATION, USER_AGENT, VARY},
+    http::StatusCode,
+    HttpRequest, HttpResponse,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use url::Url;
+
+const CONTENT_TYPE_HTML: &str = "text/html; charset=utf-8";
+
+/// How a short link redirects to its target.
+/// Stored per link, where no value means the configured default is used.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum RedirectMode {
+    #[serde(rename = "301")]
+    MovedPermanently,
+    #[serde(rename = "302")]
+    Found,
+    #[serde(rename = "307")]
+    TemporaryRedirect,
+    #[serde(rename = "308")]
+    PermanentRedirect,
+    /// Responds with a page that redirects after a delay
+    #[serde(rename = "interstitial")]
+    Interstitial,
+}
+
+impl RedirectMode {
+    pub const ALL: &'static [RedirectMode] = &[
+        RedirectMode::MovedPermanently,
+        RedirectMode::Found,
+        RedirectMode::TemporaryRedirect,
+        RedirectMode::PermanentRedirect,
+        RedirectMode::Interstitial,
+    ];
+
+    /// The string representation, as used in the database, API and config
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RedirectMode::MovedPermanently => "301",
+            RedirectMode::Found => "302",
+            RedirectMode::TemporaryRedirect => "307",
+            RedirectMode::PermanentRedirect => "308",
+            RedirectMode::Interstitial => "interstitial",
+        }
+    }
+
+    /// Permanent redirects are allowed to be cached by clients
+    fn is_permanent(self) -> bool {
+        matches!(
+            self,
+            RedirectMode::MovedPermanently | RedirectMode::PermanentRedirect
+        )
+    }
+}
+
+impl FromStr for RedirectMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<RedirectMode, ()> {
+        match s {
+            "301" => Ok(RedirectMode::MovedPermanently),
+            "302" => Ok(RedirectMode::Found),
+            "307" => Ok(RedirectMode::TemporaryRedirect),
+            "308" => Ok(RedirectMode::PermanentRedirect),
+            "interstitial" => Ok(RedirectMode::Interstitial),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Decides which clients get a page with OpenGraph and Twitter card
+/// metadata instead of a plain redirect, so that chat apps and social
+/// networks can unfurl the short link with its stored title, description
+/// and image. Stored per link, where no value means the configured
+/// default is used.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum UnfurlMode {
+    /// Only clients detected as crawlers by their user agent
+    Crawlers,
+    /// Every client, which shows the page for interstitial_delay seconds
+    Always,
+    /// No client, crawlers follow the redirect and unfurl the target
+    Never,
+}
+
+impl UnfurlMode {
+    pub const ALL: &'static [UnfurlMode] =
+        &[UnfurlMode::Crawlers, UnfurlMode::Always, UnfurlMode::Never];
+
+    /// The string representation, as used in the database, API and config
+    pub fn as_str(self) -> &'static str {
+        match self {
+            UnfurlMode::Crawlers => "crawlers",
+            UnfurlMode::Always => "always",
+            UnfurlMode::Never => "never",
+        }
+    }
+}
+
+impl FromStr for UnfurlMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<UnfurlMode, ()> {
+        match s {
+            "crawlers" => Ok(UnfurlMode::Crawlers),
+            "always" => Ok(UnfurlMode::Always),
+            "never" => Ok(UnfurlMode::Never),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Checks the user agent of req against the configured crawler user agents
+fn is_crawler(req: &HttpRequest, config: &Config) -> bool {
+    let user_agent = match req
+        .headers()
+        .get(USER_AGENT)
+        .and_then(|ua| ua.to_str().ok())
+    {
+        Some(user_agent) => user_agent.to_lowercase(),
+        None => return false,
+    };
+
+    config
+        .crawler_user_agents
+        .iter()
+        .any(|crawler| user_agent.contains(&crawler.to_lowercase()))
+}
+
+/// Appends the path suffix and query string of a request to target, for
+/// links with passthrough. suffix is the raw path after the short code.
+/// Returns None if the suffix would leave the path of the target, like
+/// `../admin` does, or if target cannot have a path.
+pub fn passthrough_url(target: &str, suffix: &str, query: &str) -> Option<String> {
+    let mut url = Url::parse(target).ok()?;
+    if url.cannot_be_a_base() {
+        return None;
+    }
+
+    if !suffix.is_empty() {
+        // set_path keeps the host and resolves dot segments
+        let base = format!("{}/", url.path().trim_end_matches('/'));
+        url.set_path(&format!("{}{}", base, suffix));
+        if !url.path().starts_with(&base) {
+            return None;
+        }
+    }
+    if !query.is_empty() {
+        let query = match url.query() {
+            Some(own) if !own.is_empty() => format!("{}&{}", own, query),
+            _ => query.to_owned(),
+        };
+        url.set_query(Some(&query));
+    }
+
+    Some(url.to_string())
+}
+
+/// Builds the redirect response for link, using its redirect mode
+/// or the configured default.
+/// Permanent redirects may be cached for the configured time, while
+/// temporary redirects and interstitial pages must not be cached at all,
+/// so that every visit reaches the service.
+/// Clients that should unfurl the link (see UnfurlMode) get a page with
+/// the link metadata instead.
+pub fn respond(req: &HttpRequest, link: &Link, config: &Config) -> HttpResponse {
+    let unfurl = link.unfurl.unwrap_or(config.unfurl);
+    let mode = match unfurl {
+        UnfurlMode::Always => RedirectMode::Interstitial,
+        UnfurlMode::Crawlers if is_crawler(req, config) => RedirectMode::Interstitial,
+        _ => link.redirect.unwrap_or(config.redirect),
+    };
+    let image = link.image.as_deref().or(config.card_image.as_deref());
+    let title = if link.title.is_empty() {
+        &link.url
+    } else {
+        &link.title
+    };
+
+    let cache_control = if mode.is_permanent() {
+        format!("public, max-age={}", config.permanent_redirect_max_age)
+    } else {
+        String::from("no-store")
+    };
+
+    let (status, delay) = match mode {
+        RedirectMode::MovedPermanently => (StatusCode::MOVED_PERMANENTLY, 0),
+        RedirectMode::Found => (StatusCode::FOUND, 0),
+        RedirectMode::TemporaryRedirect => (StatusCode::TEMPORARY_REDIRECT, 0),
+        RedirectMode::PermanentRedirect => (StatusCode::PERMANENT_REDIRECT, 0),
+        RedirectMode::Interstitial => (StatusCode::OK, config.interstitial_delay),
+    };
+
+    let mut response = HttpResponse::build(status);
+    if mode != RedirectMode::Interstitial {
+        response.header(LOCATION, link.url.clone());
+    }
+    if unfurl == UnfurlMode::Crawlers {
+        // the response depends on the user agent
+        response.header(VARY, "User-Agent");
+    }
+
+    response
+        .header(CACHE_CONTROL, cache_control)
+        .content_type(CONTENT_TYPE_HTML)
+        .body(render!(templates::redirect_html, link, title, image, delay))
+}
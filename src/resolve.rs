@@ -0,0 +1,167 @@
+//! Resolves targets that are short links themselves to their destination,
+//! so that links never point back to this service or hide their real
+//! destination behind several hops.
+//!
+//! Short links of this service are looked up in the database. Links of
+//! other shorteners are only unwrapped if configured, by requesting them
+//! without following the redirect.
+
+use super::config::{Config, ResolveConfig};
+use super::db::{self, DBError, DBValue, Pool, Queries};
+use super::fetch::Fetcher;
+use super::response_types::Error;
+use super::template;
+use url::Url;
+
+/// Resolves short link targets, see module documentation
+pub struct Resolver {
+    fetcher: Fetcher,
+    own_domains: Vec<String>,
+    /// hosts of the configured domains, which have their own link names
+    namespaces: Vec<String>,
+    config: ResolveConfig,
+}
+
+/// Normalizes a domain for comparisons
+fn normalize(domain: &str) -> String {
+    domain.trim().trim_end_matches('.').to_ascii_lowercase()
+}
+
+impl Resolver {
+    pub fn new(config: &Config) -> Resolver {
+        // with port, so that other services on the same host are not affected
+        let base_host = Url::parse(&config.base_url).ok().and_then(|url| {
+            let port = url.port_or_known_default()?;
+            url.host_str()
+                .map(|host| format!("{}:{}", normalize(host), port))
+        });
+
+        let namespaces = config
+            .domains
+            .iter()
+            .filter_map(|domain| domain.host())
+            .collect::<Vec<String>>();
+
+        Resolver {
+            fetcher: Fetcher::new(&config.fetch),
+            own_domains: config
+                .resolve
+                .own_domains
+                .iter()
+                .map(|domain| normalize(domain))
+                .chain(base_host)
+                .chain(namespaces.iter().cloned())
+                .collect(),
+            namespaces,
+            config: config.resolve.clone(),
+        }
+    }
+
+    /// Own domains match with or without port
+    pub fn is_own(&self, url: &Url) -> bool {
+        let host = url.host_str().map(normalize).unwrap_or_default();
+        let port = url.port_or_known_default().unwrap_or(0);
+
+        self.own_domains.contains(&host) || self.own_domains.contains(&format!("{}:{}", host, port))
+    }
+
+    fn is_shortener(&self, host: &str) -> bool {
+        self.config.unwrap_shorteners
+            && self
+                .config
+                .shorteners
+                .iter()
+                .any(|shortener| normalize(shortener) == host)
+    }
+
+    /// Looks up the target of a named or short link of this service
+    async fn own_target(&self, db: &Pool, url: &Url) -> Result<Url, Error> {
+        let short_code = url.path().trim_start_matches('/');
+        let unknown = || {
+            Error::invalid_target(
+                "unknown_short_link",
+                "Links to this service must point to an existing short link",
+            )
+        };
+
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+            None => url.host_str().unwrap_or_default().to_owned(),
+        };
+        let domain = Some(host).filter(|host| self.namespaces.contains(host));
+        let query = url.query().unwrap_or_default();
+        if let Some((_, target)) = template::find(db, domain, url.path(), query).await? {
+            return Url::parse(&target).map_err(|_| unknown());
+        }
+        if short_code.is_empty() || short_code.contains('/') {
+            return Err(unknown());
+        }
+
+        match db::query(db, Queries::GetLink(short_code.to_owned())).await {
+            Ok(DBValue::Link(link)) => Url::parse(&link.url).map_err(|_| unknown()),
+            Ok(value) => {
+                debug!("Got unexpected type back from GetLink query: {:#?}", value);
+                Err(Error::internal())
+            }
+            Err(err) => match err.downcast_ref::<DBError>() {
+                Some(DBError::NotFound) => Err(unknown()),
+                _ => Err(Error::from(err)),
+            },
+        }
+    }
+
+    /// Follows short links until url is not a short link anymore and
+    /// returns the final destination. Fails on loops, too many hops and
+    /// links to this service that are no short links. short_code is the
+    /// link that gets url as target, if it exists already.
+    pub async fn resolve(
+        &self,
+        db: &Pool,
+        url: Url,
+        short_code: Option<&str>,
+    ) -> Result<Url, Error> {
+        let mut url = url;
+        let mut visited = vec![url.clone()];
+
+        for _ in 0..=self.config.max_hops {
+            let host = url.host_str().map(normalize).unwrap_or_default();
+
+            let next = if self.is_own(&url) {
+                let code = url.path().trim_start_matches('/');
+                if Some(code) == short_code {
+                    return Err(Error::invalid_target(
+                        "redirect_loop",
+                        "Link cannot point to itself",
+                    ));
+                }
+                self.own_target(db, &url).await?
+            } else if self.is_shortener(&host) {
+                match self.fetcher.location(&url).await {
+                    Ok(Some(location)) => location,
+                    Ok(None) => return Ok(url),
+                    Err(err) => {
+                        debug!("Could not unwrap {}: {}", url, err);
+                        return Ok(url);
+                    }
+                }
+            } else {
+                return Ok(url);
+            };
+
+            if visited.contains(&next) {
+                return Err(Error::invalid_target(
+                    "redirect_loop",
+                    "Target redirects in a loop",
+                ));
+            }
+            debug!("Resolved {} to {}", url, next);
+            visited.push(next.clone());
+            url = next;
+        }
+
+        Err(Error::invalid_target(
+            "too_many_hops",
+            "Target redirects through too many short links",
+        ))
+    }
+}
@@ -1,15 +1,38 @@
+use std::collections::BTreeMap;
 use std::fmt::{Display, Formatter, Result as FmtResult};
 
 use actix_web::http::StatusCode;
 use actix_web::{web::HttpResponse, ResponseError};
+use schemars::JsonSchema;
 use serde::Serialize;
 use serde_json::{json, to_string_pretty};
 
-/// Error http response with the status code and a generic message.
-/// Implements everything necessary to be consumed by actix-web.
+use super::config::Config;
+use super::db::{DBError, Link};
+use super::health::Health;
+use super::redirect::{RedirectMode, UnfurlMode};
+
+/// Error http response with the status code, a stable machine readable code
+/// and a generic message. Implements everything necessary to be consumed by
+/// actix-web, see error_page.rs for the HTML and problem+json variants.
 #[derive(Debug, Serialize)]
 pub struct Error {
     pub status: u16,
+    /// stable error code like "invalid_url", which clients can match on
+    pub code: &'static str,
+    pub msg: &'static str,
+    /// the fields of the request that failed validation
+    pub fields: Vec<FieldError>,
+    /// additional fields of the JSON response
+    pub details: Option<serde_json::Value>,
+}
+
+/// A validation error of a single field of the request
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub code: &'static str,
+    #[serde(rename = "error")]
     pub msg: &'static str,
 }
 
@@ -20,32 +43,373 @@ impl Display for Error {
 }
 
 impl ResponseError for Error {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
     // builds the actix_web response
     fn error_response(&self) -> HttpResponse {
-        let err_json = json!({ "error": self.msg });
-        HttpResponse::build(StatusCode::from_u16(self.status).unwrap()).json(err_json)
+        HttpResponse::build(self.status_code()).json(self.to_json())
     }
 }
 
 impl Error {
-    /// Returns a generic error with status code 400 and the given msg
-    pub fn new(msg: &'static str) -> Error {
-        Error { status: 400, msg }
+    /// Builds the JSON body of the error response, with the details
+    /// as additional fields
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut err_json = json!({ "error": self.msg, "code": self.code });
+        if !self.fields.is_empty() {
+            err_json["errors"] = json!(self.fields);
+        }
+        if let Some(serde_json::Value::Object(details)) = &self.details {
+            for (key, value) in details {
+                err_json[key] = value.clone();
+            }
+        }
+        err_json
+    }
+
+    /// Builds the RFC 7807 problem details of the error response, with the
+    /// error code as problem type and the same additional fields as to_json
+    pub fn to_problem(&self) -> serde_json::Value {
+        let mut problem = self.to_json();
+        if let serde_json::Value::Object(fields) = &mut problem {
+            fields.remove("error");
+        }
+        problem["type"] = json!(format!("urn:k0r:error:{}", self.code));
+        problem["title"] = json!(self.msg);
+        problem["status"] = json!(self.status);
+        problem
+    }
+
+    fn with_status(status: u16, code: &'static str, msg: &'static str) -> Error {
+        Error {
+            status,
+            code,
+            msg,
+            fields: Vec::new(),
+            details: None,
+        }
+    }
+
+    /// Returns a generic client error with status code 400
+    pub fn bad_request(code: &'static str, msg: &'static str) -> Error {
+        Error::with_status(400, code, msg)
+    }
+
+    /// Returns the error for request bodies and query strings that cannot be
+    /// parsed, with the reason given by the parser, status 400
+    pub fn invalid_request(code: &'static str, msg: &'static str, reason: &str) -> Error {
+        Error {
+            details: Some(json!({ "reason": reason })),
+            ..Error::bad_request(code, msg)
+        }
+    }
+
+    /// Marks a validation error as caused by field of the request.
+    /// Authentication and internal errors are returned as they are.
+    pub fn on_field(mut self, field: &'static str) -> Error {
+        if self.is_validation() {
+            self.fields.push(FieldError {
+                field,
+                code: self.code,
+                msg: self.msg,
+            });
+        }
+        self
+    }
+
+    /// Checks if the error is caused by invalid input,
+    /// as opposed to authentication, missing entries or internal failures
+    pub fn is_validation(&self) -> bool {
+        self.status == 400 || self.status == 409
+    }
+
+    /// Returns a generic unauthorized error with status 401
+    pub fn unauthorized() -> Error {
+        Error::with_status(401, "invalid_api_key", "Invalid API key")
+    }
+
+    /// Returns a generic forbidden error with status 403
+    pub fn forbidden() -> Error {
+        Error::with_status(403, "forbidden", "Forbidden")
     }
 
     /// Returns a generic not found error with status 404
     pub fn not_found() -> Error {
+        Error::with_status(404, "not_found", "Not Found")
+    }
+
+    /// Returns the error for targets that are not allowed by the domain policy
+    pub fn domain_not_allowed(domain: &str) -> Error {
+        Error {
+            details: Some(json!({ "domain": domain })),
+            ..Error::bad_request("domain_not_allowed", "Domain not allowed")
+        }
+    }
+
+    /// Returns the error for targets with a scheme that is not allowed
+    pub fn scheme_not_allowed(scheme: &str) -> Error {
+        Error {
+            details: Some(json!({ "scheme": scheme })),
+            ..Error::bad_request("scheme_not_allowed", "URL scheme not allowed")
+        }
+    }
+
+    /// Returns a validation error for link targets with status 400
+    pub fn invalid_target(code: &'static str, msg: &'static str) -> Error {
+        Error::bad_request(code, msg)
+    }
+
+    /// Returns the error for links on domains that are not configured, status 400
+    pub fn unknown_domain(domain: &str) -> Error {
         Error {
-            status: 404,
-            msg: "Not Found",
+            details: Some(json!({ "domain": domain })),
+            ..Error::bad_request("unknown_domain", "Unknown domain")
         }
     }
 
+    /// Returns a validation error for link names with status 400
+    pub fn invalid_name(msg: &'static str) -> Error {
+        Error::bad_request("invalid_name", msg)
+    }
+
+    /// Returns the error for names that are used by another link, status 409
+    pub fn name_taken() -> Error {
+        Error::with_status(409, "name_taken", "Name already taken")
+    }
+
+    /// Returns the error for bulk requests with more than max entries, status 400
+    pub fn too_many_entries(max: usize) -> Error {
+        Error {
+            details: Some(json!({ "max_entries": max })),
+            ..Error::bad_request("too_many_entries", "Too many entries")
+        }
+    }
+
+    /// Returns the error for requests that fill the placeholders of a
+    /// template with invalid values, status 400
+    pub fn invalid_template_value() -> Error {
+        Error::bad_request("invalid_template_value", "Invalid template value")
+    }
+
+    /// Returns the error for links whose target has been blocked, status 410
+    pub fn blocked() -> Error {
+        Error::with_status(410, "link_blocked", "Link target has been blocked")
+    }
+
+    /// Returns the error for rate limited and banned clients, status 429
+    pub fn too_many_requests() -> Error {
+        Error::with_status(429, "rate_limited", "Too Many Requests")
+    }
+
     /// Returns a generic internal server error with status 500
     pub fn internal() -> Error {
-        Error {
-            status: 500,
-            msg: "Internal Server Error",
+        Error::with_status(500, "internal_error", "Internal Server Error")
+    }
+
+    /// Returns the error for requests that cannot be served because the
+    /// database is busy or unreachable, status 503
+    pub fn unavailable() -> Error {
+        Error::with_status(503, "database_unavailable", "Service Unavailable")
+    }
+}
+
+impl From<failure::Error> for Error {
+    /// translates database errors into their http counterparts
+    fn from(err: failure::Error) -> Error {
+        match err.downcast_ref::<DBError>() {
+            Some(DBError::InvalidApiKey) => Error::unauthorized(),
+            Some(DBError::PermissionDenied) => Error::forbidden(),
+            Some(DBError::NotFound) => Error::not_found(),
+            Some(DBError::Conflict) => Error::name_taken().on_field("name"),
+            Some(DBError::Unavailable(_)) | Some(DBError::Canceled) => {
+                warn!("Database unavailable: {}", err);
+                Error::unavailable()
+            }
+            _ => {
+                error!("Database query failed: {}", err);
+                Error::internal()
+            }
+        }
+    }
+}
+
+/// A stored link as returned by the API
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct LinkResponse {
+    pub code: String,
+    pub short_url: String,
+    pub target: String,
+    pub title: String,
+    pub description: String,
+    /// in UTC, like 2021-05-01T12:00:00Z
+    pub created_at: String,
+    pub visits: i64,
+    pub redirect: Option<RedirectMode>,
+    pub image: Option<String>,
+    pub unfurl: Option<UnfurlMode>,
+    pub canonical_url: Option<String>,
+    pub health: Option<Health>,
+    /// set if the target domain is not allowed by the domain policy, in UTC
+    pub blocked_at: Option<String>,
+    pub passthrough: bool,
+    /// set for vanity and template links, which are reached via their name
+    pub name: Option<String>,
+    pub domain: Option<String>,
+    /// only set when creating links, false if an existing link was returned
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created: Option<bool>,
+}
+
+impl LinkResponse {
+    pub fn new(link: Link, config: &Config) -> LinkResponse {
+        LinkResponse {
+            short_url: config.link_url(&link),
+            code: link.code,
+            target: link.url,
+            title: link.title,
+            description: link.description,
+            created_at: link.created_at,
+            visits: link.visits,
+            redirect: link.redirect,
+            image: link.image,
+            unfurl: link.unfurl,
+            canonical_url: link.canonical_url,
+            health: link.health,
+            blocked_at: link.blocked_at,
+            passthrough: link.passthrough,
+            name: link.name,
+            domain: link.domain,
+            created: None,
+        }
+    }
+}
+
+/// A page of links as returned by the API,
+/// next_cursor is only set if there are more links to fetch
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct LinkList {
+    pub links: Vec<LinkResponse>,
+    pub next_cursor: Option<String>,
+}
+
+/// Result of a single entry of a bulk link creation,
+/// either with code and short_url or with error set
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct BulkResult {
+    pub index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub short_url: Option<String>,
+    /// false if an existing link was returned
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<&'static str>,
+    /// the stable code of error, see Error
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<&'static str>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
+}
+
+impl BulkResult {
+    pub fn created(index: usize, code: String, short_url: String, is_new: bool) -> BulkResult {
+        BulkResult {
+            index,
+            short_url: Some(short_url),
+            code: Some(code),
+            created: Some(is_new),
+            error: None,
+            error_code: None,
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn failed(index: usize, error: Error) -> BulkResult {
+        BulkResult {
+            index,
+            code: None,
+            short_url: None,
+            created: None,
+            error: Some(error.msg),
+            error_code: Some(error.code),
+            errors: error.fields,
+        }
+    }
+}
+
+/// Status response of the legacy `POST /` handler
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct Status {
+    pub status: &'static str,
+    /// the short code of the link
+    pub message: String,
+    /// the stored target, which may differ from the submitted one
+    pub url: String,
+    /// false if an existing link was returned
+    pub created: bool,
+}
+
+/// Result of checking all links against a reloaded domain policy
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct RecheckResult {
+    pub blocked: usize,
+    pub unblocked: usize,
+}
+
+/// Counters of the rate limiter
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct RateLimitStats {
+    pub tracked_clients: usize,
+    pub banned_clients: usize,
+    /// requests rejected because of the rate limit
+    pub limited: u64,
+    /// clients banned for enumerating short codes
+    pub banned: u64,
+    /// requests rejected because the client is banned
+    pub rejected: u64,
+}
+
+/// Response of the liveness probe
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct Liveness {
+    pub status: &'static str,
+}
+
+/// Result of a single readiness check, error is set if it failed
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct ProbeCheck {
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl ProbeCheck {
+    pub fn new(result: Result<(), String>) -> ProbeCheck {
+        match result {
+            Ok(()) => ProbeCheck {
+                status: "ok",
+                error: None,
+            },
+            Err(error) => ProbeCheck {
+                status: "fail",
+                error: Some(error),
+            },
         }
     }
+
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// Response of the readiness probe with the result of every check,
+/// status is "ready" if all of them passed
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct Readiness {
+    pub status: &'static str,
+    pub checks: BTreeMap<&'static str, ProbeCheck>,
 }
@@ -1,18 +1,41 @@
-use super::db::{self, DBValue};
+use super::api;
+use super::client::{self, ClientResolver};
+use super::config::{Config, DomainConfig};
+use super::error_page;
+use super::cors::{self, Cors};
+use super::db::{self, DBValue, Link};
+use super::health;
+use super::metadata;
+use super::metrics::{self, Metrics};
+use super::policy::{self, Policy};
+use super::probes::{self, Probes};
+use super::proxy_protocol;
+use super::target::TargetCheck;
+use super::template;
+use super::qr::{self, QrOptions, QrQuery};
+use super::rate_limit::{self, RateLimiter};
+use super::redirect::{passthrough_url, respond as redirect_to};
+use super::resolve::Resolver;
 use super::render;
 use super::templates::{self, statics::StaticFile};
+use actix_http::{error::DispatchError, HttpService};
+use actix_service::{fn_service, map_config, pipeline_factory};
 use actix_web::{
     self,
-    http::header::{ContentType, Expires, LOCATION},
+    dev::{AppConfig, RequestHead, Service},
+    error::{JsonPayloadError, QueryPayloadError},
+    http::header::{ContentType, Expires, CONTENT_TYPE, LOCATION},
     middleware::Logger,
-    web, HttpRequest, HttpResponse, Responder,
+    web, HttpRequest, HttpResponse, Responder, ResponseError,
 };
-use std::time::{Duration, SystemTime};
+use futures::future::{ready, Either};
+use futures::TryFutureExt;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 use url::Url;
-use super::response_types::Error;
+use super::response_types::{Error, Liveness, Status};
 
 const CONTENT_TYPE_HTML: &str = "text/html; charset=utf-8";
-const CONTENT_TYPE_JSON: &str = "application/json; charset=utf-8";
 
 /// A duration to add to current time for a far expires header.
 const FAR: Duration = Duration::from_secs(180 * 24 * 60 * 60);
@@ -21,25 +44,129 @@ const FAR: Duration = Duration::from_secs(180 * 24 * 60 * 60);
 /// Common, unsoliticed queries by browsers that should be ignored
 const IGNORED_SHORT_CODES: &[&str] = &["favicon.ico"];
 
-type DB = web::Data<db::Pool>;
-type JSON = web::Json<db::UrlPostData>;
+pub type DB = web::Data<db::Pool>;
+pub type Cfg = web::Data<Config>;
+pub type MetadataQueue = web::Data<metadata::Queue>;
+pub type DomainPolicy = web::Data<Policy>;
+pub type Resolve = web::Data<Resolver>;
+pub type Limiter = web::Data<RateLimiter>;
+pub type Stats = web::Data<Metrics>;
+type Json = web::Json<db::UrlPostData>;
 
-fn get_request_origin(req: &HttpRequest) -> String {
-    req.connection_info()
-        .remote_addr()
-        .unwrap_or("unkown origin")
-        .to_string()
+/// Like the default format of the Logger, but with the client address
+/// resolved by the ClientResolver instead of the peer address
+const LOG_FORMAT: &str = r#"%{r}a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T"#;
+
+pub fn get_request_origin(req: &HttpRequest) -> String {
+    client::address(req.head()).map_or_else(|| String::from("unkown origin"), |ip| ip.to_string())
+}
+
+
+/// Describes the expected structure of the shorten form on the index page
+#[derive(Default, serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
+#[serde(default)]
+pub struct ShortenForm {
+    pub url: String,
+    pub title: String,
+    pub description: String,
+    pub key: String,
+}
+
+/// A validation error of the shorten form, shown next to its field
+/// or above the form if field is empty
+pub struct FormError {
+    pub field: &'static str,
+    pub msg: &'static str,
+}
+
+impl FormError {
+    /// Returns the error message if error belongs to field
+    pub fn message(error: Option<&FormError>, field: &str) -> Option<&'static str> {
+        error.filter(|err| err.field == field).map(|err| err.msg)
+    }
+}
+
+/// Responds to JSON bodies that cannot be parsed with a structured error,
+/// too large bodies keep their 413 Payload Too Large
+fn invalid_json(err: JsonPayloadError, _: &HttpRequest) -> actix_web::Error {
+    match err {
+        JsonPayloadError::Overflow => err.into(),
+        err => Error::invalid_request("invalid_json", "Invalid JSON", &err.to_string()).into(),
+    }
+}
+
+/// Responds to query strings that cannot be parsed with a structured error
+fn invalid_query(err: QueryPayloadError, _: &HttpRequest) -> actix_web::Error {
+    Error::invalid_request("invalid_query", "Invalid query string", &err.to_string()).into()
+}
+
+/// Guards handlers that accept form data
+fn is_form(req: &RequestHead) -> bool {
+    req.headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.starts_with("application/x-www-form-urlencoded"))
+        .unwrap_or(false)
+}
+
+/// Query of the search box on error pages
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
+pub struct SearchQuery {
+    pub q: Option<String>,
 }
 
+/// Builds the preview path for a search like `1z5`, `docs` or a pasted
+/// short URL like `https://k0r.eu/1z5`. Names only exist in the namespace
+/// of their domain, so pasted short URLs of another configured domain get
+/// the absolute preview URL on that domain.
+fn search_path(search: &str, config: &Config) -> Option<String> {
+    let search = search.trim();
+    let (path, domain) = match Url::parse(search) {
+        Ok(url) => {
+            let host = url.host_str().map(|host| match url.port() {
+                Some(port) => format!("{}:{}", host, port),
+                None => host.to_owned(),
+            });
+            let domain = host.as_deref().and_then(|host| config.domain(host));
+            (url.path().to_owned(), domain)
+        }
+        Err(_) => (search.to_owned(), None),
+    };
+    let path = path.trim_matches('/').trim_end_matches('+');
+    let is_valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '/';
+
+    if path.is_empty() || !path.chars().all(is_valid) {
+        return None;
+    }
+    Some(match domain {
+        Some(domain) => format!("{}/{}+", domain.base_url.trim_end_matches('/'), path),
+        None => format!("/{}+", path),
+    })
+}
 
 /// Index page handler
 /// `GET /`
-/// returns the static template from templates/index.rs.html
+/// returns the template from templates/index.rs.html with an empty form.
+/// Searches from error pages like `GET /?q=1z5` redirect to the preview.
 #[actix_web::get("/")]
-async fn index() -> HttpResponse {
+async fn index(req: HttpRequest, query: web::Query<SearchQuery>, config: Cfg) -> HttpResponse {
+    if let Some(path) = query.q.as_deref().and_then(|q| search_path(q, &config)) {
+        return HttpResponse::SeeOther().header(LOCATION, path).finish();
+    }
+
+    let index = config
+        .domain(req.connection_info().host())
+        .and_then(|domain| domain.index.clone());
+
+    if let Some(path) = index {
+        match metrics::block(move || std::fs::read_to_string(&path)).await {
+            Ok(html) => return HttpResponse::Ok().content_type(CONTENT_TYPE_HTML).body(html),
+            Err(err) => error!("Could not read index page: {}", err),
+        }
+    }
     HttpResponse::Ok()
         .content_type(CONTENT_TYPE_HTML)
-        .body(render!(templates::index))
+        .body(render!(templates::index_html, &ShortenForm::default(), None))
 }
 
 /// Handler for static files.
@@ -62,41 +189,232 @@ fn static_file(path: web::Path<String>) -> HttpResponse {
     }
 }
 
+/// Looks up the link for a request to path, like redirect does: named links
+/// on the domain of the Host header take precedence over short codes, see
+/// template.rs. As names never start with a short code, see db::insert_url,
+/// names are only looked up if there is no link with the first segment of
+/// path as short code. The url of the returned link is replaced with the
+/// target for this path, with template placeholders filled in or passthrough
+/// applied. lookup is the query for short codes, GetURL to count a visit or
+/// GetLink.
+async fn resolve(
+    req: &HttpRequest,
+    db: &DB,
+    config: &Cfg,
+    path: &str,
+    lookup: fn(String) -> db::Queries,
+) -> Result<Box<Link>, Error> {
+    let path = path.trim_start_matches('/');
+    let (first, suffix) = match path.split_once('/') {
+        Some((first, suffix)) => (first, Some(suffix)),
+        None => (path, None),
+    };
+    if IGNORED_SHORT_CODES.contains(&first) {
+        debug!("{} queried {}: IGNORED", get_request_origin(req), first);
+        return Err(Error::not_found());
+    }
+
+    let mut named = None;
+    let mut found = find_code(db, first, lookup).await;
+    if found.is_none() {
+        let domain = config
+            .domain(req.connection_info().host())
+            .and_then(DomainConfig::host);
+        named = template::find(db, domain, path, req.query_string()).await?;
+        if let Some((code, _)) = &named {
+            rate_limit::mark_named(req);
+            found = find_code(db, code, lookup).await;
+        }
+    }
+
+    let mut link = match found {
+        Some(link) => link,
+        None => {
+            debug!("{} queried {}, got Not Found", get_request_origin(req), path);
+            return Err(Error::not_found());
+        }
+    };
+    debug!(
+        "{} queried {}, got {}",
+        get_request_origin(req),
+        &link.code,
+        &link.url
+    );
+    if link.blocked_at.is_some() {
+        return Err(Error::blocked());
+    }
+    if let Some((_, target)) = named {
+        link.url = target;
+    } else if link.passthrough {
+        let suffix = suffix.unwrap_or_default();
+        link.url = passthrough_url(&link.url, suffix, req.query_string())
+            .ok_or_else(Error::not_found)?;
+    } else if suffix.is_some() {
+        return Err(Error::not_found());
+    }
+    Ok(link)
+}
+
+/// Queries the link with short_code using lookup, without a round trip to
+/// the database for paths that cannot be short codes
+async fn find_code(
+    db: &DB,
+    short_code: &str,
+    lookup: fn(String) -> db::Queries,
+) -> Option<Box<Link>> {
+    let is_code = !short_code.is_empty()
+        && short_code
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric());
+    if !is_code {
+        return None;
+    }
+    match db::query(db, lookup(short_code.to_owned())).await {
+        Ok(DBValue::Link(link)) => Some(link),
+        _ => None,
+    }
+}
+
+/// Looks up the link named exactly like path on the domain of the Host
+/// header, which shows templates like `/gh+` without filling them in
+async fn find_named(
+    req: &HttpRequest,
+    db: &DB,
+    config: &Cfg,
+    path: &str,
+) -> Result<Option<Box<Link>>, Error> {
+    if IGNORED_SHORT_CODES.contains(&path) {
+        return Ok(None);
+    }
+    let domain = config
+        .domain(req.connection_info().host())
+        .and_then(DomainConfig::host);
+    let link = template::find_exact(db, domain, path).await?;
+    if link.is_some() {
+        rate_limit::mark_named(req);
+    }
+    Ok(link)
+}
+
 /// Shortcode handler
-/// `GET /1z5`
+/// `GET /1z5` or `GET /1z5/any/path?x=1`
 /// Asks the database for the URL matching short_code and responds
-/// with a redirect or, if not found, a JSON error
-#[actix_web::get("/{short_code}")]
-async fn redirect(req: HttpRequest, db: DB) -> Result<HttpResponse, Error> {
-    let short_code = req.match_info().get("short_code").unwrap_or("0");
-
-    if IGNORED_SHORT_CODES.contains(&short_code) {
-        debug!(
-            "{} queried {}: IGNORED",
-            get_request_origin(&req),
-            short_code
-        );
-        Err(Error::not_found())
-    } else if let Ok(DBValue::String(url)) =
-        db::query(&db, db::Queries::GetURL(short_code.to_owned())).await
-    {
-        debug!(
-            "{} queried {}, got {}",
-            get_request_origin(&req),
-            &short_code,
-            &url
-        );
-        Ok(HttpResponse::MovedPermanently()
-            .header(LOCATION, url.clone())
-            .content_type(CONTENT_TYPE_HTML)
-            .body(render!(templates::redirect, "redirect", &url)))
-    } else {
-        debug!(
-            "{} queried {}, got Not Found",
-            get_request_origin(&req),
-            short_code
-        );
-        Err(Error::not_found())
+/// with a redirect (see redirect::respond) or, if not found, a JSON error.
+/// Path suffixes and query strings are only forwarded to the target of
+/// links with passthrough, other links do not exist with a path suffix.
+/// Named links like `GET /docs` or `GET /gh/k0r` on the domain of the Host
+/// header take precedence over short codes, see template.rs.
+async fn redirect(req: HttpRequest, db: DB, config: Cfg) -> Result<HttpResponse, Error> {
+    let mut link = resolve(&req, &db, &config, req.path(), db::Queries::GetURL).await?;
+    if let Some(fallback) = health::fallback_url(&link, &config) {
+        debug!("{} is dead, redirecting to {}", &link.url, &fallback);
+        link.url = fallback;
+    }
+    Ok(redirect_to(&req, &link, &config))
+}
+
+/// Preview handler
+/// `GET /1z5+`, `GET /docs+`, `GET /gh/k0r+` or `GET /1z5/preview`
+/// Shows where the short link leads to, without counting a visit.
+/// Templates named exactly like the path are shown as they are.
+async fn preview(req: HttpRequest, db: DB, config: Cfg) -> Result<HttpResponse, Error> {
+    let path = req.match_info().get("short_code").unwrap_or("0");
+    let link = match find_named(&req, &db, &config, path).await? {
+        Some(link) => link,
+        None => resolve(&req, &db, &config, path, db::Queries::GetLink).await?,
+    };
+    if link.blocked_at.is_some() {
+        return Err(Error::blocked());
+    }
+
+    let short_url = config.link_url(&link);
+    let host = Url::parse(&link.url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_owned))
+        .unwrap_or_default();
+
+    Ok(HttpResponse::Ok()
+        .content_type(CONTENT_TYPE_HTML)
+        .body(render!(templates::preview_html, &link, &short_url, &host)))
+}
+
+/// QR code handler
+/// `GET /1z5.svg`, `GET /docs.png`, `GET /gh/issue.svg` or
+/// `GET /1z5/qr?format=png`
+/// Renders the absolute short URL as QR code, customizable with the
+/// query parameters described in qr::QrQuery. The image never changes,
+/// so it gets a far expires header, just like static files.
+/// Paths with several segments that are no name, like `/1z5/image.png`,
+/// are path suffixes of passthrough links and get redirected instead.
+async fn qr_code(
+    req: HttpRequest,
+    query: web::Query<QrQuery>,
+    db: DB,
+    config: Cfg,
+) -> Result<HttpResponse, Error> {
+    let path = req.match_info().get("short_code").unwrap_or("0");
+    let format = req
+        .match_info()
+        .get("format")
+        .or_else(|| query.format.as_deref())
+        .unwrap_or("svg");
+    let options = QrOptions::from_query(&query)
+        .map_err(|msg| Error::bad_request("invalid_qr_options", msg))?;
+
+    let link = match find_named(&req, &db, &config, path).await? {
+        Some(link) => link,
+        None if path.contains('/') => return redirect(req, db, config).await,
+        None => match db::query(&db, db::Queries::GetLink(path.to_owned())).await? {
+            DBValue::Link(link) => link,
+            value => {
+                debug!("Got unexpected type back from GetLink query: {:#?}", value);
+                return Err(Error::internal());
+            }
+        },
+    };
+    let short_url = config.link_url(&link);
+
+    let too_long = |msg| Error::bad_request("qr_data_too_long", msg);
+    let mut response = HttpResponse::Ok();
+    response.set(Expires((SystemTime::now() + FAR).into()));
+
+    match format {
+        "svg" => Ok(response
+            .content_type("image/svg+xml")
+            .body(qr::svg(&short_url, &options).map_err(too_long)?)),
+        "png" => Ok(response
+            .content_type("image/png")
+            .body(qr::png(&short_url, &options).map_err(too_long)?)),
+        _ => Err(Error::bad_request("unknown_qr_format", "Unknown QR code format")),
+    }
+}
+
+/// Checks that url is parseable and not path only or a data URL,
+/// the origin of req is used for logging
+pub fn validate_url(req: &HttpRequest, url: &str) -> Result<Url, Error> {
+    match Url::parse(url) {
+        Ok(parsed_url) => {
+            if !parsed_url.has_authority() {
+                debug!(
+                    "{} posted \"{}\", got Invalid, no authority.",
+                    get_request_origin(req),
+                    url
+                );
+                return Err(Error::bad_request(
+                    "invalid_url",
+                    "Invalid URL, cannot be path only or data URL",
+                ));
+            }
+            Ok(parsed_url)
+        }
+        Err(_) => {
+            debug!(
+                "{} posted \"{}\", got Invalid, Parser Error.",
+                get_request_origin(req),
+                url
+            );
+            Err(Error::bad_request("invalid_url", "Invalid URL"))
+        }
     }
 }
 
@@ -108,60 +426,589 @@ async fn redirect(req: HttpRequest, db: DB) -> Result<HttpResponse, Error> {
 ///   description: an optional description for the URL, defaults to empty string,
 ///   key: the API key
 #[actix_web::post("/")]
-async fn add_url(_req: HttpRequest, data: JSON, db: DB) -> Result<impl Responder, Error> {
-    match Url::parse(&data.url) {
-        Ok(parsed_url) => {
-            if !parsed_url.has_authority() {
-                debug!(
-                    "{} posted \"{}\", got Invalid, no authority.",
-                    get_request_origin(&_req),
-                    &data.url
-                );
-                return Err(Error::new("Invalid URL, cannot be path only or data URL"));
-            }
+async fn add_url(
+    req: HttpRequest,
+    data: Json,
+    db: DB,
+    config: Cfg,
+    queue: MetadataQueue,
+    policy: DomainPolicy,
+    resolver: Resolve,
+) -> Result<impl Responder, Error> {
+    let mut data = data.into_inner();
+    let targets = TargetCheck::new(&req, &db, &config, &resolver, &policy, &data.key).await?;
+    data.link = targets.check_link(data.link).await?;
 
-            let query_result = db::query(&db, db::Queries::StoreNewURL(data.into_inner())).await;
-
-            match query_result {
-                Ok(DBValue::String(code)) => Ok(HttpResponse::Created()
-                    .content_type(CONTENT_TYPE_JSON)
-                    .body(format!("{{\"status\": \"ok\", \"message\": \"{}\"}}", code))),
-                Err(_) => Err(Error::new("Invalid API key")),
-                _ => {
-                    debug!(
-                        "Got unexpected type back from StoreNewURL query: {:#?}",
-                        query_result
-                    );
-                    Err(Error::internal())
-                }
-            }
+    let link = data.link.clone();
+    let query_result = db::query(&db, db::Queries::StoreNewURL(data, config.deduplicate)).await;
+
+    match query_result {
+        Ok(DBValue::Stored(mut stored)) if stored.len() == 1 => {
+            let (code, is_new) = stored.remove(0);
+            let mut response = if is_new {
+                queue.push(&code, &link);
+                HttpResponse::Created()
+            } else {
+                HttpResponse::Ok()
+            };
+            Ok(response.json(Status {
+                status: "ok",
+                message: code,
+                url: link.url,
+                created: is_new,
+            }))
         }
-        Err(_) => {
+        Err(err) => Err(err.into()),
+        _ => {
             debug!(
-                "{} posted \"{}\", got Invalid, Parser Error.",
-                get_request_origin(&_req),
-                &data.url
+                "Got unexpected type back from StoreNewURL query: {:#?}",
+                query_result
             );
-            Err(Error::new("Invalid URL"))
+            Err(Error::internal())
+        }
+    }
+}
+
+/// URL Form Handler
+/// POST / -H 'Content-Type: application/x-www-form-urlencoded'
+/// with the same fields as the JSON handler. Responds with the result page
+/// or the index page with the validation error shown inline.
+#[actix_web::post("/", guard = "is_form")]
+async fn add_url_form(
+    req: HttpRequest,
+    form: web::Form<ShortenForm>,
+    db: DB,
+    config: Cfg,
+    queue: MetadataQueue,
+    policy: DomainPolicy,
+    resolver: Resolve,
+) -> HttpResponse {
+    let form = form.into_inner();
+    // shows validation errors next to their field, invalid keys next to
+    // the key field and all other errors above the form
+    let error = |err: Error| {
+        let field = match err.fields.first() {
+            Some(field) => field.field,
+            None if err.status == 401 => "key",
+            None => "",
+        };
+        HttpResponse::build(err.status_code())
+            .content_type(CONTENT_TYPE_HTML)
+            .body(render!(
+                templates::index_html,
+                &form,
+                Some(&FormError {
+                    field,
+                    msg: err.msg
+                })
+            ))
+    };
+
+    let targets = match TargetCheck::new(&req, &db, &config, &resolver, &policy, &form.key).await {
+        Ok(targets) => targets,
+        Err(err) => return error(err),
+    };
+    let target = match targets.check(&form.url, None).await {
+        Ok(target) => target,
+        Err(err) => return error(err.on_field("url")),
+    };
+
+    let non_empty = |value: &str| Some(value.to_owned()).filter(|v| !v.is_empty());
+    let data = db::UrlPostData {
+        link: db::LinkPostData {
+            url: target.clone(),
+            title: non_empty(&form.title),
+            description: non_empty(&form.description),
+            redirect: None,
+            image: None,
+            unfurl: None,
+            passthrough: None,
+            name: None,
+            domain: None,
+        },
+        key: form.key.clone(),
+    };
+    let link = data.link.clone();
+
+    match db::query(&db, db::Queries::StoreNewURL(data, config.deduplicate)).await {
+        Ok(DBValue::Stored(mut stored)) if stored.len() == 1 => {
+            let (code, is_new) = stored.remove(0);
+            let mut response = if is_new {
+                queue.push(&code, &link);
+                HttpResponse::Created()
+            } else {
+                HttpResponse::Ok()
+            };
+            response
+                .content_type(CONTENT_TYPE_HTML)
+                .body(render!(
+                    templates::result_html,
+                    &config.short_url(&code),
+                    &target,
+                    &code
+                ))
+        }
+        Err(err) => error(err.into()),
+        Ok(value) => {
+            debug!("Got unexpected type back from StoreNewURL query: {:#?}", value);
+            error(Error::internal())
         }
     }
 }
 
+/// Metrics handler
+/// `GET /metrics`
+/// responds with the metrics of the service in the Prometheus text format,
+/// see metrics.rs. Only routed if enabled, possibly on its own address.
+async fn export_metrics(db: DB, measure: Stats, limiter: Limiter) -> HttpResponse {
+    let database_size = match db::query(&db, db::Queries::DatabaseSize).await {
+        Ok(DBValue::Number(size)) => Some(size),
+        Ok(value) => {
+            debug!("Got unexpected type back from DatabaseSize query: {:#?}", value);
+            None
+        }
+        Err(err) => {
+            debug!("Could not get the database size: {}", err);
+            None
+        }
+    };
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4; charset=utf-8")
+        .body(measure.render(&db, database_size, &limiter.stats()))
+}
+
+/// Liveness probe handler
+/// `GET /healthz`
+/// responds with 200 OK as long as the service handles requests
+async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(Liveness { status: "ok" })
+}
+
+/// Readiness probe handler
+/// `GET /readyz`
+/// runs the readiness checks of probes.rs and responds with the result of
+/// each, with 503 Service Unavailable if any of them failed
+async fn readyz(db: DB, probes: web::Data<Probes>) -> HttpResponse {
+    let readiness = probes.readiness(&db).await;
+    let mut response = if readiness.status == "ready" {
+        HttpResponse::Ok()
+    } else {
+        HttpResponse::ServiceUnavailable()
+    };
+    response.json(readiness)
+}
+
 /// the web service initiator
 #[actix_web::main]
-pub async fn start(db_pool: db::Pool) -> std::io::Result<()> {
-    println!("Server is listening on 127.0.0.1:8080");
+pub async fn start(
+    db_pool: db::Pool,
+    config: Config,
+    policy: Policy,
+    clients: ClientResolver,
+    metrics: Arc<Metrics>,
+) -> std::io::Result<()> {
+    println!("Server is listening on {}", config.listen);
+
+    let listen = config.listen.clone();
+    let queue = metadata::Queue::start(db_pool.clone(), &config.metadata, &config.fetch);
+    health::start(db_pool.clone(), &config.health, &config.fetch);
+    let policy = web::Data::new(policy);
+
+    // links to domains that were blocked while the service was down
+    let (pool, rules) = (db_pool.clone(), policy.current());
+    actix_web::rt::spawn(async move {
+        match policy::recheck_links(&pool, &rules).await {
+            Ok((blocked, unblocked)) => {
+                debug!("Blocked {} and unblocked {} links", blocked, unblocked)
+            }
+            Err(err) => error!("Could not check links against the domain policy: {}", err),
+        }
+    });
+    let clients = web::Data::new(clients);
+    let cors = web::Data::new(Cors::new(&config.cors));
+    let (proxy_protocol, protocol_timeout) = (config.proxy.protocol, config.proxy.protocol_timeout);
+    let measure = web::Data::from(metrics);
+    // metrics are served with the service, unless they have their own address
+    let metrics_path = Some(config.metrics.path.clone())
+        .filter(|_| config.metrics.enabled && config.metrics.listen.is_none());
+    let limiter = web::Data::new(RateLimiter::new(
+        &config.rate_limit,
+        metrics_path.iter().cloned().collect(),
+    ));
+    rate_limit::start(limiter.clone().into_inner());
+    if let (true, Some(listen)) = (config.metrics.enabled, &config.metrics.listen) {
+        let (db_pool, measure, limiter) = (db_pool.clone(), measure.clone(), limiter.clone());
+        let path = config.metrics.path.clone();
+        println!("Metrics are served on {}", listen);
+        let server = actix_web::HttpServer::new(move || {
+            actix_web::App::new()
+                .data(db_pool.clone())
+                .app_data(measure.clone())
+                .app_data(limiter.clone())
+                .route(&path, web::get().to(export_metrics))
+        })
+        .workers(1)
+        .disable_signals()
+        .bind(listen)?
+        .run();
+        // runs alongside the service until it stops
+        actix_web::rt::spawn(async move {
+            if let Err(err) = server.await {
+                error!("Metrics server failed: {}", err);
+            }
+        });
+    }
+    let probes = web::Data::new(Probes::new(&config.probes));
+    let shutdown_probes = probes.clone().into_inner();
+    let config = web::Data::new(config);
+
+    let app = move || {
+        let rate_limiter = limiter.clone();
+        let resolver = clients.clone();
+        let cors = cors.clone();
+        let recorder = measure.clone();
+        let metrics_path = metrics_path.clone();
 
-    actix_web::HttpServer::new(move || {
         actix_web::App::new()
-            .wrap(Logger::default())
+            .wrap_fn(move |req, srv| match rate_limit::limit(&rate_limiter, req) {
+                Err(response) => Either::Left(ready(Ok(response))),
+                Ok((req, client)) => {
+                    let limiter = rate_limiter.clone();
+                    let response = srv.call(req);
+                    Either::Right(async move {
+                        let response = response.await?;
+                        if let Some(client) = client {
+                            let code = rate_limit::looked_up_code(&response);
+                            limiter.record(client, code, response.status());
+                        }
+                        Ok(response)
+                    })
+                }
+            })
+            .wrap_fn(move |req, srv| match cors::preflight(&cors, req) {
+                Err(response) => Either::Left(ready(Ok(response))),
+                Ok((req, allow_origin)) => {
+                    let cors = cors.clone();
+                    let response = srv.call(req);
+                    Either::Right(async move {
+                        let mut response = response.await?;
+                        if let Some(allow_origin) = allow_origin {
+                            cors::allow(&cors, allow_origin, &mut response);
+                        }
+                        Ok(response)
+                    })
+                }
+            })
+            .wrap_fn(|req, srv| srv.call(req).map_ok(error_page::negotiate))
+            .wrap_fn(move |req, srv| {
+                let (measure, start) = (recorder.clone(), Instant::now());
+                srv.call(req).map_ok(move |response| {
+                    measure.record(&response, start.elapsed());
+                    response
+                })
+            })
+            .wrap(
+                probes::PATHS
+                    .iter()
+                    .fold(Logger::new(LOG_FORMAT), |logger, path| logger.exclude(*path)),
+            )
+            .wrap_fn(move |mut req, srv| {
+                resolver.resolve_request(&mut req);
+                srv.call(req)
+            })
             .data(db_pool.clone())
+            .data(queue.clone())
+            .data(Resolver::new(&config))
+            .app_data(policy.clone())
+            .app_data(config.clone())
+            .app_data(limiter.clone())
+            .app_data(measure.clone())
+            .app_data(probes.clone())
+            .app_data(web::JsonConfig::default().error_handler(invalid_json))
+            .app_data(web::QueryConfig::default().error_handler(invalid_query))
             .service(static_file) // GET /static/file.xyz
+            .service(api::scope()) // /api/v1/…
             .service(index) // GET /
-            .service(redirect) // GET /123
-            .service(add_url) // POST /
-    })
-    .bind(("127.0.0.1", 8080))?
-    .run()
-    .await
+            .route("/healthz", web::get().to(healthz)) // GET /healthz
+            .route("/readyz", web::get().to(readyz)) // GET /readyz
+            .configure(|cfg| {
+                if let Some(path) = metrics_path {
+                    cfg.route(&path, web::get().to(export_metrics)); // GET /metrics
+                }
+            })
+            .route("/{short_code:.+}.{format:(svg|png)}", web::get().to(qr_code)) // GET /123.svg
+            .route("/{short_code}/qr", web::get().to(qr_code)) // GET /123/qr
+            .route("/{short_code:.+}+", web::get().to(preview)) // GET /123+
+            .route("/{short_code}/preview", web::get().to(preview)) // GET /123/preview
+            .route("/{short_code}", web::get().to(redirect)) // GET /123
+            .route("/{short_code}/{path:.*}", web::get().to(redirect)) // GET /123/any/path
+            .service(add_url_form) // POST / (form data)
+            .service(add_url) // POST / (JSON)
+    };
+
+    // with a shutdown delay, signals are handled by the probes
+    let delays_shutdown = shutdown_probes.delays_shutdown();
+    let server = if !proxy_protocol {
+        let server = actix_web::HttpServer::new(app);
+        let server = if delays_shutdown {
+            server.disable_signals()
+        } else {
+            server
+        };
+        server.bind(listen)?.run()
+    } else {
+        // the HttpServer cannot read the PROXY header before HTTP, so the
+        // connections are handed to the HTTP service after reading it
+        let server = actix_server::Server::build();
+        let server = if delays_shutdown {
+            server.disable_signals()
+        } else {
+            server
+        };
+        server
+            .bind("k0r", listen, move || {
+                let app = map_config(app(), |_| AppConfig::default());
+                pipeline_factory(fn_service(move |stream| async move {
+                    proxy_protocol::accept(stream, protocol_timeout)
+                        .await
+                        .map_err(|err| {
+                            debug!("Rejected connection: {}", err);
+                            DispatchError::Io(err)
+                        })
+                }))
+                .and_then(HttpService::build().h1(app))
+            })?
+            .run()
+    };
+
+    if delays_shutdown {
+        probes::shutdown_on_signal(server.clone(), shutdown_probes);
+    }
+    server.await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::openapi;
+    use actix_web::{
+        dev::{MessageBody, ServiceResponse},
+        http::header::AUTHORIZATION,
+        test::{self, TestRequest},
+        App,
+    };
+    use serde_json::{json, Value};
+
+    fn config() -> Config {
+        Config::from_toml(
+            r#"
+            [[domains]]
+            base_url = "https://go.example.com"
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn searches_codes_and_names() {
+        let config = config();
+        assert_eq!(search_path("1z5", &config).as_deref(), Some("/1z5+"));
+        assert_eq!(search_path(" docs+ ", &config).as_deref(), Some("/docs+"));
+        assert_eq!(search_path("gh/issue", &config).as_deref(), Some("/gh/issue+"));
+    }
+
+    #[test]
+    fn searches_pasted_short_urls() {
+        let config = config();
+        assert_eq!(
+            search_path("https://k0r.eu/1z5", &config).as_deref(),
+            Some("/1z5+")
+        );
+        assert_eq!(
+            search_path("https://GO.example.com/gh/issue/", &config).as_deref(),
+            Some("https://go.example.com/gh/issue+")
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_searches() {
+        let config = config();
+        assert_eq!(search_path("", &config), None);
+        assert_eq!(search_path("https://k0r.eu/", &config), None);
+        assert_eq!(search_path("a b", &config), None);
+        // never a protocol relative URL to another host
+        assert_eq!(search_path("//evil.example/x", &config), None);
+    }
+
+    /// Sends req to app and checks the response against the endpoint at
+    /// the documented path, returns its status and JSON body
+    async fn call<S, B>(app: &mut S, method: &str, path: &str, req: TestRequest) -> (u16, Value)
+    where
+        S: Service<
+            Request = actix_http::Request,
+            Response = ServiceResponse<B>,
+            Error = actix_web::Error,
+        >,
+        B: MessageBody + Unpin,
+    {
+        let response = test::call_service(app, req.to_request()).await;
+        let status = response.status().as_u16();
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_owned();
+        let body = test::read_body(response).await;
+        openapi::tests::check_response(method, path, status, &content_type, &body);
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    fn with_key(req: TestRequest, key: &str) -> TestRequest {
+        req.header(AUTHORIZATION, format!("Bearer {}", key))
+    }
+
+    #[actix_rt::test]
+    async fn responses_match_the_openapi_document() {
+        let (pool, admin) = db::tests::test_db();
+        let (owner, other) = (db::tests::user(&pool, false), db::tests::user(&pool, false));
+        let config = Config::default();
+        let mut app = test::init_service(
+            App::new()
+                .data(pool.clone())
+                .data(metadata::Queue::start(pool, &config.metadata, &config.fetch))
+                .data(Resolver::new(&config))
+                .app_data(web::Data::new(Policy::load(None, &config.schemes).unwrap()))
+                .app_data(web::Data::new(RateLimiter::new(&config.rate_limit, Vec::new())))
+                .app_data(web::Data::new(Metrics::new()))
+                .app_data(web::Data::new(Probes::new(&config.probes)))
+                .app_data(web::Data::new(config))
+                .app_data(web::JsonConfig::default().error_handler(invalid_json))
+                .app_data(web::QueryConfig::default().error_handler(invalid_query))
+                .service(api::scope())
+                .service(index)
+                .route("/healthz", web::get().to(healthz))
+                .route("/readyz", web::get().to(readyz))
+                .route("/metrics", web::get().to(export_metrics))
+                .route("/{short_code:.+}.{format:(svg|png)}", web::get().to(qr_code))
+                .route("/{short_code}/qr", web::get().to(qr_code))
+                .route("/{short_code:.+}+", web::get().to(preview))
+                .route("/{short_code}/preview", web::get().to(preview))
+                .route("/{short_code}", web::get().to(redirect))
+                .route("/{short_code}/{path:.*}", web::get().to(redirect))
+                .service(add_url_form)
+                .service(add_url),
+        )
+        .await;
+
+        // API
+        let (links, link) = ("/api/v1/links", "/api/v1/links/{short_code}");
+        let mut codes = Vec::new();
+        for redirect in &["301", "302", "307", "308"] {
+            let body = json!({ "url": "https://example.com/", "redirect": redirect });
+            let req = with_key(TestRequest::post().uri(links), &owner).set_json(&body);
+            let (status, created) = call(&mut app, "post", links, req).await;
+            assert_eq!(status, 201);
+            codes.push(created["code"].as_str().unwrap().to_owned());
+        }
+        let body =
+            json!({ "url": "https://example.com/docs", "name": "docs", "passthrough": true });
+        let req = with_key(TestRequest::post().uri(links), &owner).set_json(&body);
+        assert_eq!(call(&mut app, "post", links, req).await.0, 201);
+        let req = with_key(TestRequest::post().uri(links), &owner).set_json(&json!({ "url": "x" }));
+        assert_eq!(call(&mut app, "post", links, req).await.0, 400);
+        let req = TestRequest::post().uri(links).set_json(&json!({ "url": "https://a.example" }));
+        assert_eq!(call(&mut app, "post", links, req).await.0, 401);
+
+        let bulk = "/api/v1/links/bulk";
+        let body = json!([{ "url": "https://example.com/bulk" }, { "url": "x" }]);
+        let req = with_key(TestRequest::post().uri(bulk), &owner).set_json(&body);
+        let (status, results) = call(&mut app, "post", bulk, req).await;
+        assert_eq!(status, 200);
+
+        let req = with_key(TestRequest::get().uri("/api/v1/links?limit=2"), &owner);
+        let (status, list) = call(&mut app, "get", links, req).await;
+        assert_eq!((status, list["links"].as_array().map(Vec::len)), (200, Some(2)));
+        let req = with_key(TestRequest::get().uri("/api/v1/links?limit=x"), &owner);
+        assert_eq!(call(&mut app, "get", links, req).await.0, 400);
+
+        let uri = format!("{}/{}", links, results[0]["code"].as_str().unwrap());
+        assert_eq!(call(&mut app, "get", link, TestRequest::get().uri(&uri)).await.0, 200);
+        let req = TestRequest::get().uri("/api/v1/links/zzzzz");
+        assert_eq!(call(&mut app, "get", link, req).await.0, 404);
+        let body = json!({ "title": "Updated", "redirect": null, "image": null, "unfurl": null });
+        let req = with_key(TestRequest::patch().uri(&uri), &other).set_json(&body);
+        assert_eq!(call(&mut app, "patch", link, req).await.0, 403);
+        let req = with_key(TestRequest::patch().uri(&uri), &owner).set_json(&body);
+        let (status, patched) = call(&mut app, "patch", link, req).await;
+        assert_eq!((status, patched["redirect"].clone()), (200, Value::Null));
+        let req = with_key(TestRequest::delete().uri(&uri), &other);
+        assert_eq!(call(&mut app, "delete", link, req).await.0, 403);
+        let req = with_key(TestRequest::delete().uri(&uri), &owner);
+        assert_eq!(call(&mut app, "delete", link, req).await.0, 204);
+
+        let broken = "/api/v1/admin/links/broken";
+        let req = with_key(TestRequest::get().uri(broken), &owner);
+        assert_eq!(call(&mut app, "get", broken, req).await.0, 403);
+        let req = with_key(TestRequest::get().uri(broken), &admin);
+        assert_eq!(call(&mut app, "get", broken, req).await.0, 200);
+        let stats = "/api/v1/admin/rate-limit";
+        let req = with_key(TestRequest::get().uri(stats), &admin);
+        assert_eq!(call(&mut app, "get", stats, req).await.0, 200);
+        for path in &["/api/v1/openapi.json", "/api/v1/docs"] {
+            assert_eq!(call(&mut app, "get", path, TestRequest::get().uri(path)).await.0, 200);
+        }
+
+        // legacy API and index page
+        let body = json!({ "url": "https://example.com/legacy", "key": owner });
+        let (status, legacy) =
+            call(&mut app, "post", "/", TestRequest::post().set_json(&body)).await;
+        assert_eq!(status, 201);
+        assert!(!legacy["message"].as_str().unwrap().contains('/'));
+        let form = ShortenForm {
+            url: String::from("https://example.com/form"),
+            key: owner.clone(),
+            ..ShortenForm::default()
+        };
+        let req = TestRequest::post().set_form(&form);
+        assert_eq!(call(&mut app, "post", "/", req).await.0, 201);
+        let form = ShortenForm {
+            key: String::from("invalid"),
+            ..form
+        };
+        let req = TestRequest::post().set_form(&form);
+        assert_eq!(call(&mut app, "post", "/", req).await.0, 401);
+        assert_eq!(call(&mut app, "get", "/", TestRequest::get()).await.0, 200);
+        let req = TestRequest::get().uri("/?q=1z5");
+        assert_eq!(call(&mut app, "get", "/", req).await.0, 303);
+
+        // short links
+        for (code, expected) in codes.iter().zip(&[301, 302, 307, 308]) {
+            let req = TestRequest::get().uri(&format!("/{}", code));
+            assert_eq!(call(&mut app, "get", "/{short_code}", req).await.0, *expected);
+        }
+        let req = TestRequest::get().uri("/docs");
+        assert_eq!(call(&mut app, "get", "/{short_code}", req).await.0, 302);
+        let req = TestRequest::get().uri("/docs/more");
+        assert_eq!(call(&mut app, "get", "/{short_code}/{path}", req).await.0, 302);
+        let req = TestRequest::get().uri("/docs+");
+        assert_eq!(call(&mut app, "get", "/{short_code}+", req).await.0, 200);
+        let req = TestRequest::get().uri(&format!("/{}/preview", codes[0]));
+        assert_eq!(call(&mut app, "get", "/{short_code}/preview", req).await.0, 200);
+        let req = TestRequest::get().uri("/zzzzz+");
+        assert_eq!(call(&mut app, "get", "/{short_code}+", req).await.0, 404);
+        for format in &["svg", "png"] {
+            let req = TestRequest::get().uri(&format!("/{}.{}", codes[1], format));
+            assert_eq!(call(&mut app, "get", "/{short_code}.{format}", req).await.0, 200);
+        }
+        let req = TestRequest::get().uri(&format!("/{}/qr?format=png", codes[1]));
+        assert_eq!(call(&mut app, "get", "/{short_code}/qr", req).await.0, 200);
+        let req = TestRequest::get().uri(&format!("/{}/qr?size=x", codes[1]));
+        assert_eq!(call(&mut app, "get", "/{short_code}/qr", req).await.0, 400);
+
+        // operations
+        for path in &["/healthz", "/readyz", "/metrics"] {
+            call(&mut app, "get", path, TestRequest::get().uri(path)).await;
+        }
+    }
 }
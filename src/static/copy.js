@@ -0,0 +1,10 @@
+// copies the value of the input referenced by data-target to the clipboard
+document.querySelectorAll('button.copy').forEach(function (button) {
+  button.addEventListener('click', function () {
+    var input = document.getElementById(button.dataset.target)
+    input.select()
+    navigator.clipboard.writeText(input.value).then(function () {
+      button.textContent = 'Copied!'
+    })
+  })
+})
@@ -0,0 +1,123 @@
+use super::config::Config;
+use super::db::{LinkPostData, Pool};
+use super::normalize::normalize;
+use super::policy::{self, DomainPolicy, Policy};
+use super::resolve::Resolver;
+use super::response_types::Error;
+use super::server::validate_url;
+use super::template;
+use actix_web::HttpRequest;
+use std::sync::Arc;
+
+/// Checks the submitted link targets of one user: the URL is validated,
+/// short links are resolved to their destination, the destination is
+/// checked against the domain policy and normalized if configured.
+/// Used by every handler that stores link targets.
+/// Targets of template links are checked with their placeholders filled in.
+pub struct TargetCheck<'a> {
+    req: &'a HttpRequest,
+    db: &'a Pool,
+    config: &'a Config,
+    resolver: &'a Resolver,
+    rules: Arc<DomainPolicy>,
+    user_id: i64,
+}
+
+impl<'a> TargetCheck<'a> {
+    /// Prepares checks for the user assigned to api_key
+    pub async fn new(
+        req: &'a HttpRequest,
+        db: &'a Pool,
+        config: &'a Config,
+        resolver: &'a Resolver,
+        policy: &Policy,
+        api_key: &str,
+    ) -> Result<TargetCheck<'a>, Error> {
+        let rules = policy.current();
+        // only user rules and domains depend on the user
+        let user_id = if rules.has_user_rules() || !config.domains.is_empty() {
+            policy::user_id(db, api_key).await?
+        } else {
+            0
+        };
+
+        Ok(TargetCheck {
+            req,
+            db,
+            config,
+            resolver,
+            rules,
+            user_id,
+        })
+    }
+
+    /// Checks url and returns the target to store. short_code is the link
+    /// that gets the new target, if it exists already.
+    pub async fn check(&self, url: &str, short_code: Option<&str>) -> Result<String, Error> {
+        let parsed = validate_url(self.req, url)?;
+        let resolved = self
+            .resolver
+            .resolve(self.db, parsed.clone(), short_code)
+            .await?;
+        self.rules.check(self.user_id, &resolved)?;
+
+        if self.config.normalize.enabled {
+            Ok(normalize(&resolved, &self.config.normalize).to_string())
+        } else if resolved == parsed {
+            Ok(url.to_owned())
+        } else {
+            Ok(resolved.to_string())
+        }
+    }
+
+    /// Checks the target template of a link, see template.rs. It must not
+    /// point to this service, as its destination cannot be resolved.
+    pub async fn check_template(&self, template: &str) -> Result<String, Error> {
+        let example = validate_url(self.req, &template::example(template)?)?;
+        if self.resolver.is_own(&example) {
+            return Err(Error::invalid_target(
+                "redirect_loop",
+                "Templates cannot point to this service",
+            ));
+        }
+        self.rules.check(self.user_id, &example)?;
+        Ok(template.trim().to_owned())
+    }
+
+    /// Checks that the user may create links on the configured domain with
+    /// the given host and returns the host as stored
+    fn check_domain(&self, host: &str) -> Result<String, Error> {
+        let domain = self
+            .config
+            .domain(host)
+            .ok_or_else(|| Error::unknown_domain(host))?;
+        if !domain.users.contains(&self.user_id) {
+            return Err(Error::forbidden());
+        }
+        Ok(domain.host().unwrap_or_default())
+    }
+
+    /// Checks the target, name, domain and image of a new link.
+    /// Validation errors name the field that failed.
+    pub async fn check_link(&self, mut link: LinkPostData) -> Result<LinkPostData, Error> {
+        if let Some(host) = &link.domain {
+            link.domain = Some(
+                self.check_domain(host)
+                    .map_err(|err| err.on_field("domain"))?,
+            );
+        }
+        if let Some(name) = &link.name {
+            link.name = Some(template::validate_name(name).map_err(|err| err.on_field("name"))?);
+        }
+        let url = if link.name.is_some() && template::is_template(&link.url) {
+            self.check_template(&link.url).await
+        } else {
+            self.check(&link.url, None).await
+        };
+        link.url = url.map_err(|err| err.on_field("url"))?;
+        if let Some(image) = &link.image {
+            validate_url(self.req, image).map_err(|err| err.on_field("image"))?;
+        }
+        Ok(link)
+    }
+}
@@ -0,0 +1,366 @@
+//! Named links: vanity links with a name like `docs`, and go-link style
+//! templates with a name like `gh` and a target with named placeholders like
+//! `https://github.com/org/{repo}`, reached via `/gh/k0r`. The path segments
+//! after the name fill the placeholders in the order they first appear in
+//! the target. Every configured domain has its own namespace of names.
+//!
+//! Placeholders are only allowed after the host, so that templates cannot
+//! redirect to other domains than the one checked against the domain policy.
+//! Filled in values are restricted to unreserved URL characters.
+
+use super::db::{self, DBValue, Link, Pool, Queries};
+use super::redirect::passthrough_url;
+use super::response_types::Error;
+
+/// First path segments that are used by other routes
+const RESERVED_NAMES: &[&str] = &["api", "static", "metrics", "healthz", "readyz"];
+
+/// Last path segments of the QR code and preview routes, like `/1z5/qr`
+const RESERVED_SUFFIXES: &[&str] = &["qr", "preview"];
+
+/// Returns the distinct placeholder names of template in the order of
+/// their first appearance, or None if a brace or a name is invalid
+pub fn placeholders(template: &str) -> Option<Vec<&str>> {
+    let mut names = Vec::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find(&['{', '}'][..]) {
+        if rest[start..].starts_with('}') {
+            return None;
+        }
+        let end = start + rest[start..].find('}')?;
+        let name = &rest[start + 1..end];
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return None;
+        }
+        if !names.contains(&name) {
+            names.push(name);
+        }
+        rest = &rest[end + 1..];
+    }
+    Some(names)
+}
+
+/// Checks if url is a template, invalid placeholders included
+pub fn is_template(url: &str) -> bool {
+    !matches!(placeholders(url), Some(names) if names.is_empty())
+}
+
+/// Returns the path of a link on its domain, which is the name of vanity
+/// links and the short code of all others
+pub fn path<'a>(code: &'a str, name: Option<&'a str>, target: &str) -> &'a str {
+    match name {
+        Some(name) if !is_template(target) => name,
+        _ => code,
+    }
+}
+
+/// Replaces every placeholder of template with its value
+fn fill(template: &str, names: &[&str], values: &[&str]) -> String {
+    names
+        .iter()
+        .zip(values)
+        .fold(template.to_owned(), |url, (name, value)| {
+            url.replace(&format!("{{{}}}", name), value)
+        })
+}
+
+/// Values may only contain unreserved URL characters, so that they
+/// cannot change the structure of the target
+fn is_valid_value(value: &str) -> bool {
+    !value.is_empty()
+        && value.len() <= 200
+        && value != "."
+        && value != ".."
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-._~".contains(c))
+}
+
+/// Validates and lowercases the name of a link, which consists of
+/// one or more path segments like `gh` or `jira/issue`
+pub fn validate_name(name: &str) -> Result<String, Error> {
+    let name = name.trim_matches('/').to_ascii_lowercase();
+    let is_valid_segment = |segment: &str| {
+        !segment.is_empty()
+            && segment
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    };
+
+    if name.is_empty() || name.len() > 64 || !name.split('/').all(is_valid_segment) {
+        return Err(Error::invalid_name(
+            "Names consist of letters, digits, - and _, separated by /",
+        ));
+    }
+    if RESERVED_NAMES.contains(&name.split('/').next().unwrap_or_default()) {
+        return Err(Error::invalid_name("Name is reserved"));
+    }
+    let last = name.rsplit('/').next().unwrap_or_default();
+    if name.contains('/') && RESERVED_SUFFIXES.contains(&last) {
+        return Err(Error::invalid_name(
+            "Name ends like a QR code or preview route",
+        ));
+    }
+    Ok(name)
+}
+
+/// Checks the syntax of template and returns an example target with all
+/// placeholders filled in, to check it like any other target
+pub fn example(template: &str) -> Result<String, Error> {
+    let names = placeholders(template).ok_or_else(|| {
+        Error::invalid_target("invalid_template", "Template has invalid placeholders")
+    })?;
+    if names.is_empty() {
+        return Err(Error::invalid_target(
+            "invalid_template",
+            "Template needs at least one placeholder",
+        ));
+    }
+
+    let host = template
+        .find("://")
+        .map(|start| &template[start + 3..])
+        .and_then(|rest| rest.split(&['/', '?', '#'][..]).next());
+    match host {
+        Some(host) if !host.is_empty() && !host.contains('{') => {}
+        _ => {
+            return Err(Error::invalid_target(
+                "invalid_template",
+                "Placeholders are only allowed after the host",
+            ))
+        }
+    }
+
+    let values = vec!["x"; names.len()];
+    Ok(fill(template, &names, &values))
+}
+
+/// Looks up the link on domain that is named exactly like path, to show
+/// templates like `/gh+` as they are instead of filling them in
+pub async fn find_exact(
+    db: &Pool,
+    domain: Option<String>,
+    path: &str,
+) -> Result<Option<Box<Link>>, Error> {
+    let name = path.trim_matches('/').to_ascii_lowercase();
+
+    match db::query(db, Queries::FindNamed(domain, vec![name])).await? {
+        DBValue::Links(links, _) => Ok(links.into_iter().next().map(Box::new)),
+        value => {
+            debug!(
+                "Got unexpected type back from FindNamed query: {:#?}",
+                value
+            );
+            Err(Error::internal())
+        }
+    }
+}
+
+/// Looks up the named link on domain for a request to path and returns its
+/// short code and target, where the longest matching name wins. Templates
+/// only match if the remaining segments fill all of their placeholders,
+/// vanity links only if there are none left or if they have passthrough.
+pub async fn find(
+    db: &Pool,
+    domain: Option<String>,
+    path: &str,
+    query: &str,
+) -> Result<Option<(String, String)>, Error> {
+    let path = path.trim_start_matches('/');
+    let segments = path.trim_end_matches('/').split('/').collect::<Vec<&str>>();
+    let names = (1..=segments.len())
+        .map(|n| segments[..n].join("/").to_ascii_lowercase())
+        .collect::<Vec<String>>();
+
+    let mut links = match db::query(db, Queries::FindNamed(domain, names)).await? {
+        DBValue::Links(links, _) => links,
+        value => {
+            debug!(
+                "Got unexpected type back from FindNamed query: {:#?}",
+                value
+            );
+            return Err(Error::internal());
+        }
+    };
+    links.sort_by_key(|link| std::cmp::Reverse(link.name.as_deref().unwrap_or("").len()));
+
+    for link in links {
+        let matched = link.name.as_deref().unwrap_or("").split('/').count();
+        let values = &segments[matched..];
+        let names = placeholders(&link.url).unwrap_or_default();
+
+        if names.is_empty() && values.is_empty() && !link.passthrough {
+            return Ok(Some((link.code, link.url)));
+        }
+        if names.is_empty() && link.passthrough {
+            let suffix = path.splitn(matched + 1, '/').nth(matched).unwrap_or("");
+            let target = passthrough_url(&link.url, suffix, query).ok_or_else(Error::not_found)?;
+            return Ok(Some((link.code, target)));
+        }
+        if names.is_empty() || names.len() != values.len() {
+            continue;
+        }
+
+        if let Some(value) = values.iter().find(|value| !is_valid_value(value)) {
+            debug!("Invalid value {} for template {}", value, link.code);
+            return Err(Error::invalid_template_value());
+        }
+        return Ok(Some((link.code, fill(&link.url, &names, values))));
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::test_db;
+    use crate::db::{LinkPostData, UrlPostData};
+
+    #[test]
+    fn validates_names() {
+        assert_eq!(validate_name("Docs").unwrap(), "docs");
+        assert_eq!(validate_name("/jira/Issue/").unwrap(), "jira/issue");
+        assert_eq!(validate_name("a-b_c/1").unwrap(), "a-b_c/1");
+        assert_eq!(validate_name(&"a".repeat(64)).unwrap(), "a".repeat(64));
+
+        for name in &["", "/", "a//b", "a b", "a.b", "ü", "a?b", &"a".repeat(65)] {
+            let err = validate_name(name).unwrap_err();
+            assert_eq!((err.status, err.code), (400, "invalid_name"), "{}", name);
+        }
+        for name in &["api", "Static/x", "metrics", "healthz", "readyz"] {
+            let err = validate_name(name).unwrap_err();
+            assert_eq!(err.msg, "Name is reserved", "{}", name);
+        }
+        assert!(validate_name("apis").is_ok());
+
+        for name in &["foo/qr", "foo/Preview", "a/b/qr"] {
+            let err = validate_name(name).unwrap_err();
+            assert_eq!(err.code, "invalid_name", "{}", name);
+        }
+        assert!(validate_name("qr").is_ok());
+        assert!(validate_name("qr/foo").is_ok());
+        assert!(validate_name("foo/qrcode").is_ok());
+    }
+
+    #[test]
+    fn finds_placeholders() {
+        assert_eq!(
+            placeholders("https://x.org/{a}/{b_1}?q={a}"),
+            Some(vec!["a", "b_1"])
+        );
+        assert_eq!(placeholders("https://x.org/"), Some(vec![]));
+        for template in &[
+            "https://x.org/{",
+            "https://x.org/}",
+            "/{}",
+            "/{a-b}",
+            "/{a{b}}",
+        ] {
+            assert_eq!(placeholders(template), None, "{}", template);
+        }
+        assert!(is_template("https://x.org/{a"));
+        assert!(!is_template("https://x.org/a"));
+    }
+
+    #[test]
+    fn checks_templates() {
+        assert_eq!(
+            example("https://github.com/{org}/{repo}").unwrap(),
+            "https://github.com/x/x"
+        );
+        for template in &[
+            "https://github.com/",
+            "https://{host}/",
+            "https://github.{tld}/{repo}",
+            "//{host}",
+            "https://github.com/{repo",
+        ] {
+            let err = example(template).unwrap_err();
+            assert_eq!(err.code, "invalid_template", "{}", template);
+        }
+    }
+
+    #[test]
+    fn validates_values() {
+        assert!(is_valid_value("k0r-1.2_~"));
+        for value in &["", ".", "..", "a/b", "a%2F", "a?b", "ü", &"a".repeat(201)] {
+            assert!(!is_valid_value(value), "{}", value);
+        }
+    }
+
+    async fn store(pool: &Pool, key: &str, url: &str, name: &str, passthrough: bool) {
+        let link = LinkPostData {
+            url: url.to_owned(),
+            title: None,
+            description: None,
+            redirect: None,
+            image: None,
+            unfurl: None,
+            passthrough: Some(passthrough),
+            name: Some(name.to_owned()),
+            domain: None,
+        };
+        let data = UrlPostData {
+            link,
+            key: key.to_owned(),
+        };
+        db::query(pool, Queries::StoreNewURL(data, false))
+            .await
+            .unwrap();
+    }
+
+    async fn target(pool: &Pool, path: &str, query: &str) -> Option<String> {
+        find(pool, None, path, query)
+            .await
+            .unwrap()
+            .map(|(_, target)| target)
+    }
+
+    #[actix_rt::test]
+    async fn finds_named_links() {
+        let (pool, key) = test_db();
+        store(&pool, &key, "https://docs.example.com/", "docs", false).await;
+        store(&pool, &key, "https://github.com/{repo}", "gh", false).await;
+        store(
+            &pool,
+            &key,
+            "https://github.com/{org}/{repo}",
+            "gh/org",
+            false,
+        )
+        .await;
+        store(&pool, &key, "https://wiki.example.com/", "wiki", true).await;
+
+        let target = |path, query| target(&pool, path, query);
+        assert_eq!(
+            target("/Docs", "").await.as_deref(),
+            Some("https://docs.example.com/")
+        );
+        assert_eq!(target("/docs/more", "").await, None);
+        assert_eq!(
+            target("/gh/k0r", "").await.as_deref(),
+            Some("https://github.com/k0r")
+        );
+        // the longest matching name wins
+        assert_eq!(
+            target("/gh/org/rust/k0r", "").await.as_deref(),
+            Some("https://github.com/rust/k0r")
+        );
+        // templates only match if all placeholders are filled
+        assert_eq!(target("/gh", "").await, None);
+        assert_eq!(target("/gh/a/b/c/d", "").await, None);
+        assert_eq!(
+            target("/wiki/a/b", "c=d").await.as_deref(),
+            Some("https://wiki.example.com/a/b?c=d")
+        );
+        assert_eq!(target("/unknown", "").await, None);
+
+        let err = find(&pool, None, "/gh/..", "").await.unwrap_err();
+        assert_eq!(err.status, 400);
+
+        let link = find_exact(&pool, None, "/GH/").await.unwrap().unwrap();
+        assert_eq!(link.url, "https://github.com/{repo}");
+        assert!(find_exact(&pool, None, "/gh/k0r").await.unwrap().is_none());
+    }
+}